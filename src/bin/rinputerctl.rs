@@ -0,0 +1,316 @@
+use std::{
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::ExitCode,
+};
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+
+static USAGE: &str = "Usage: rinputerctl [options] <command> [args]
+Commands:
+  list-sinks                 Lists sinks and the sources bound to them
+  list-sink-types            Lists sink types that can be added
+  list-sources               Lists input devices that can be bound to sinks
+  add-sink <type> [source]   Adds a sink, waits for L+R on a controller if no source is given
+  del-sink <id>              Removes a sink
+  rebind-sink <id> [source]  Feeds a sink from another source, waits for L+R if none is given
+  sink-stats <id>            Shows event rate, queue depth and latency of a sink
+  sink-settings <id>         Shows the settings of a sink
+  set-sink-setting <id> <key> <value>
+                             Changes a setting, e.g. rumble off, rumble_intensity 50 or motion on
+  monitor <sink-or-source>   Prints events of a sink or source until interrupted
+  shutdown                   Stops the daemon
+Options:
+  --socket <path>            Path of the daemon's control socket
+  --tcp <addr>               Connect over TCP instead, e.g. 127.0.0.1:4000
+  --json                     Print raw JSON results
+  --help                     Displays this message
+";
+
+const PROTOCOL_VERSION: u32 = 1;
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+struct Client {
+    reader: BufReader<Box<dyn Stream>>,
+    next_id: u64,
+}
+
+impl Client {
+    fn new(stream: Box<dyn Stream>) -> Result<Self> {
+        let mut ret = Self { reader: BufReader::new(stream), next_id: 0 };
+        ret.request(json!({ "cmd": "hello", "version": PROTOCOL_VERSION }))?;
+        Ok(ret)
+    }
+
+    fn read_reply(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("Daemon closed the connection");
+        }
+        // denied clients get a plain text line before the socket is closed
+        serde_json::from_str(&line)
+            .map_err(|_| anyhow!("{}", line.trim()))
+    }
+
+    // Sends one request and returns its result, daemon errors become Err
+    fn request(&mut self, mut request: Value) -> Result<Value> {
+        self.next_id += 1;
+        request["id"] = json!(self.next_id);
+        let mut line = request.to_string();
+        line.push('\n');
+        self.reader.get_mut().write_all(line.as_bytes())?;
+
+        loop {
+            let reply = self.read_reply()?;
+            if reply["id"] == json!(self.next_id) {
+                return into_result(reply);
+            }
+        }
+    }
+
+    // Prints monitor events as they come, only returns on errors
+    fn stream_monitor(&mut self, json_output: bool) -> Result<()> {
+        loop {
+            let ev = self.read_reply()?;
+            if ev["event"] != json!("monitor") {
+                continue;
+            }
+            if json_output {
+                println!("{}", ev);
+            } else {
+                println!("{:.6} {} {:<6} {:<4} {:<18} {}", ev["time"].as_f64().unwrap_or(0.0),
+                    text(&ev["target"]), text(&ev["stage"]), text(&ev["type"]), text(&ev["code"]), ev["value"]);
+            }
+        }
+    }
+
+    fn wait_for_pairing(&mut self, pairing: &Value) -> Result<Value> {
+        loop {
+            let reply = self.read_reply()?;
+            if reply.get("id").is_none() && &reply["pairing"] == pairing {
+                return into_result(reply);
+            }
+        }
+    }
+}
+
+fn into_result(reply: Value) -> Result<Value> {
+    if reply["ok"] == json!(true) {
+        return Ok(reply["result"].clone());
+    }
+    let error = &reply["error"];
+    bail!("{} ({})", error["message"].as_str().unwrap_or("Unknown error"),
+        error["code"].as_str().unwrap_or("unknown"))
+}
+
+// RINPUTER_SOCKET only tells this tool where to look, the daemon goes by
+// --socket. Its default is the system socket when run as root and one in
+// XDG_RUNTIME_DIR otherwise, the system one is the usual setup.
+fn socket_candidates() -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    if let Some(path) = env::var_os("RINPUTER_SOCKET") {
+        ret.push(PathBuf::from(path));
+    }
+    ret.push(PathBuf::from("/run/rinputer/rinputer4.sock"));
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        ret.push(PathBuf::from(runtime_dir).join("rinputer4.sock"));
+    }
+    ret
+}
+
+fn connect(socket: Option<PathBuf>, tcp: Option<String>) -> Result<Box<dyn Stream>> {
+    if let Some(addr) = tcp {
+        return Ok(Box::new(TcpStream::connect(&addr)
+            .map_err(|e| anyhow!("Can't connect to {}: {}", addr, e))?));
+    }
+    if let Some(path) = socket {
+        return Ok(Box::new(UnixStream::connect(&path)
+            .map_err(|e| anyhow!("Can't connect to {}: {}", path.display(), e))?));
+    }
+
+    for path in socket_candidates() {
+        if let Ok(stream) = UnixStream::connect(&path) {
+            return Ok(Box::new(stream));
+        }
+    }
+    bail!("Can't find a running rinputer4, is the daemon started?")
+}
+
+fn print_sinks(result: &Value) {
+    let sinks = result["sinks"].as_array().cloned().unwrap_or_default();
+    if sinks.is_empty() {
+        println!("No sinks");
+    }
+    for sink in sinks {
+        println!("{}: {} <- {} ({}, player {})", sink["id"], text(&sink["name"]), text(&sink["source"]),
+            text(&sink["caps"]), sink["player"]);
+    }
+}
+
+fn print_sink_types(result: &Value) {
+    for sink_type in result["sink_types"].as_array().cloned().unwrap_or_default() {
+        println!("{}: {}", sink_type["id"], text(&sink_type["name"]));
+    }
+}
+
+fn print_sources(result: &Value) {
+    let sources = result["sources"].as_array().cloned().unwrap_or_default();
+    if sources.is_empty() {
+        println!("No sources");
+    }
+    for source in sources {
+        let bound = match source["sink"].as_u64() {
+            Some(sink) => format!(" (sink {})", sink),
+            None => String::new(),
+        };
+        println!("{}: {} [{:04x}:{:04x}]{}", text(&source["id"]), text(&source["name"]),
+            source["vendor"].as_u64().unwrap_or(0), source["product"].as_u64().unwrap_or(0), bound);
+    }
+}
+
+fn print_stats(result: &Value) {
+    let stats = &result["stats"];
+    let or_dash = |v: &Value| v.as_u64().map_or("-".to_string(), |v| v.to_string());
+    println!("Events forwarded: {}", stats["events_forwarded"]);
+    println!("Events per second: {:.1}", stats["events_per_second"].as_f64().unwrap_or(0.0));
+    println!("Last event: {} ms ago", or_dash(&stats["last_event_ms_ago"]));
+    println!("Queue depth: {}", stats["queue_depth"]);
+    println!("Latency: {} us average, {} us max",
+        or_dash(&stats["latency_avg_us"]), or_dash(&stats["latency_max_us"]));
+    let per_frame = stats["writes_per_frame"].as_f64().map_or("-".to_string(), |v| format!("{:.2}", v));
+    println!("Frames forwarded: {}, {} writes per frame", stats["frames_forwarded"], per_frame);
+}
+
+fn print_settings(result: &Value) {
+    if let Some(settings) = result["settings"].as_object() {
+        for (key, value) in settings {
+            println!("{}: {}", key, text(value));
+        }
+    }
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or("")
+}
+
+fn parse_number(arg: Option<&String>, what: &str) -> Result<u64> {
+    let arg = arg.ok_or_else(|| anyhow!("Missing {}\n{}", what, USAGE))?;
+    arg.parse().map_err(|_| anyhow!("Invalid {} {}", what, arg))
+}
+
+fn run() -> Result<()> {
+    let mut socket = None;
+    let mut tcp = None;
+    let mut json_output = false;
+    let mut command = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" => {
+                print!("{}", USAGE);
+                return Ok(());
+            },
+            "--json" => json_output = true,
+            "--socket" => socket = Some(PathBuf::from(args.next()
+                .ok_or_else(|| anyhow!("Missing value for --socket"))?)),
+            "--tcp" => tcp = Some(args.next()
+                .ok_or_else(|| anyhow!("Missing value for --tcp"))?),
+            _ => command.push(arg),
+        }
+    }
+
+    let name = command.first()
+        .ok_or_else(|| anyhow!("Missing command\n{}", USAGE))?
+        .clone();
+    let mut client = Client::new(connect(socket, tcp)?)?;
+
+    let (result, print): (Value, fn(&Value)) = match name.as_str() {
+        "list-sinks" => (client.request(json!({ "cmd": "list_sinks" }))?, print_sinks),
+        "list-sink-types" => (client.request(json!({ "cmd": "list_sink_types" }))?, print_sink_types),
+        "list-sources" => (client.request(json!({ "cmd": "list_sources" }))?, print_sources),
+        "add-sink" => {
+            let sink_type = parse_number(command.get(1), "sink type")?;
+            let mut request = json!({ "cmd": "add_sink", "sink_type": sink_type });
+            if let Some(source) = command.get(2) {
+                request["source"] = json!(source);
+            }
+
+            let mut result = client.request(request)?;
+            if let Some(pairing) = result.get("pairing").cloned() {
+                if !json_output {
+                    println!("Press L+R on the controller to use");
+                }
+                result = client.wait_for_pairing(&pairing)?;
+            }
+            (result, |r| println!("Added sink {}", r["sink"]))
+        },
+        "rebind-sink" => {
+            let sink = parse_number(command.get(1), "sink ID")?;
+            let mut request = json!({ "cmd": "rebind_sink", "sink": sink });
+            if let Some(source) = command.get(2) {
+                request["source"] = json!(source);
+            }
+
+            let mut result = client.request(request)?;
+            if let Some(pairing) = result.get("pairing").cloned() {
+                if !json_output {
+                    println!("Press L+R on the controller to use");
+                }
+                result = client.wait_for_pairing(&pairing)?;
+            }
+            (result, |r| println!("Rebound sink {}", r["sink"]))
+        },
+        "del-sink" => {
+            let sink = parse_number(command.get(1), "sink ID")?;
+            (client.request(json!({ "cmd": "del_sink", "sink": sink }))?, |_| println!("Removed"))
+        },
+        "sink-stats" => {
+            let sink = parse_number(command.get(1), "sink ID")?;
+            (client.request(json!({ "cmd": "sink_stats", "sink": sink }))?, print_stats)
+        },
+        "sink-settings" => {
+            let sink = parse_number(command.get(1), "sink ID")?;
+            (client.request(json!({ "cmd": "sink_settings", "sink": sink }))?, print_settings)
+        },
+        "set-sink-setting" => {
+            let sink = parse_number(command.get(1), "sink ID")?;
+            let (key, value) = match (command.get(2), command.get(3)) {
+                (Some(key), Some(value)) => (key, value),
+                _ => bail!("Missing setting or value\n{}", USAGE),
+            };
+            let request = json!({ "cmd": "set_sink_setting", "sink": sink, "key": key, "value": value });
+            (client.request(request)?, |_| println!("Changed"))
+        },
+        "monitor" => {
+            let target = command.get(1)
+                .ok_or_else(|| anyhow!("Missing sink or source\n{}", USAGE))?;
+            client.request(json!({ "cmd": "monitor", "target": target }))?;
+            return client.stream_monitor(json_output);
+        },
+        "shutdown" => (client.request(json!({ "cmd": "shutdown" }))?, |_| println!("Shutting down")),
+        _ => bail!("Unknown command {}\n{}", name, USAGE),
+    };
+
+    if json_output {
+        println!("{}", result);
+    } else {
+        print(&result);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rinputerctl: {}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    env,
+    path::PathBuf,
+    time::Duration,
+};
+use nix::unistd::{
+    self,
+    Gid,
+    Group,
+    Uid,
+    User,
+};
+use anyhow::{anyhow, bail, Result};
+
+use crate::sink::profile;
+
+static USAGE: &str = "Usage: rinputer4 [options]
+  --socket <path>        Path of the control socket
+  --allow-uid <user>     Allow a user (name or uid) to use the control socket
+  --allow-group <group>  Allow members of a group (name or gid) to use the control socket
+  --tcp <addr>           Also serve the control protocol over TCP, e.g. 127.0.0.1:0
+  --pairing-timeout <s>  Seconds add_sink waits for L+R before giving up (default 60)
+  --state-file <path>    Where sink bindings are saved and restored from
+  --log <spec>           Log filter like info,source=debug (default $RINPUTER_LOG or info)
+  --log-journal          Log to the systemd journal instead of stderr
+  --receive <addr>       Take pads sent by Network sinks over UDP and TCP, e.g. 0.0.0.0:7941
+  --receive-as <type>    Sink type received pads show up as (default Gamepad device)
+  --psk-file <path>      Only take pads signed with the key in this file
+  --help                 Displays this message
+";
+
+pub struct Config {
+    pub socket_path: PathBuf,
+    pub tcp_addr: Option<String>,
+    pub allowed_uids: Vec<Uid>,
+    pub allowed_gids: Vec<Gid>,
+    pub pairing_timeout: Duration,
+    pub state_path: PathBuf,
+    pub log_filter: String,
+    pub log_journal: bool,
+    pub receive_addr: Option<String>,
+    pub receive_as: String,
+    pub psk_file: Option<PathBuf>,
+}
+
+fn default_socket_path() -> PathBuf {
+    if !unistd::geteuid().is_root() {
+        if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+            return PathBuf::from(runtime_dir).join("rinputer4.sock");
+        }
+    }
+    PathBuf::from("/run/rinputer/rinputer4.sock")
+}
+
+fn default_state_path() -> PathBuf {
+    if !unistd::geteuid().is_root() {
+        if let Some(state_dir) = env::var_os("XDG_STATE_HOME") {
+            return PathBuf::from(state_dir).join("rinputer4/state.json");
+        }
+        if let Some(home) = env::var_os("HOME") {
+            return PathBuf::from(home).join(".local/state/rinputer4/state.json");
+        }
+    }
+    PathBuf::from("/var/lib/rinputer/state.json")
+}
+
+fn parse_uid(input: &str) -> Result<Uid> {
+    if let Ok(raw) = input.parse::<u32>() {
+        return Ok(Uid::from_raw(raw));
+    }
+    User::from_name(input)?
+        .map(|u| u.uid)
+        .ok_or_else(|| anyhow!("Unknown user {}", input))
+}
+
+fn parse_gid(input: &str) -> Result<Gid> {
+    if let Ok(raw) = input.parse::<u32>() {
+        return Ok(Gid::from_raw(raw));
+    }
+    Group::from_name(input)?
+        .map(|g| g.gid)
+        .ok_or_else(|| anyhow!("Unknown group {}", input))
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self> {
+        let mut ret = Config {
+            socket_path: default_socket_path(),
+            tcp_addr: None,
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+            pairing_timeout: Duration::from_secs(60),
+            state_path: default_state_path(),
+            log_filter: env::var("RINPUTER_LOG").unwrap_or_else(|_| "info".to_string()),
+            log_journal: false,
+            receive_addr: None,
+            receive_as: profile::XBOX_360.name.to_string(),
+            psk_file: None,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            if arg == "--log-journal" {
+                ret.log_journal = true;
+                continue;
+            }
+
+            let value = args.next()
+                .ok_or_else(|| anyhow!("Missing value for {}\n{}", arg, USAGE))?;
+            match arg.as_str() {
+                "--socket" => ret.socket_path = PathBuf::from(value),
+                "--allow-uid" => ret.allowed_uids.push(parse_uid(&value)?),
+                "--allow-group" => ret.allowed_gids.push(parse_gid(&value)?),
+                "--tcp" => ret.tcp_addr = Some(value),
+                "--pairing-timeout" => ret.pairing_timeout = Duration::from_secs(value.parse()?),
+                "--state-file" => ret.state_path = PathBuf::from(value),
+                "--log" => ret.log_filter = value,
+                "--receive" => ret.receive_addr = Some(value),
+                "--receive-as" => ret.receive_as = value,
+                "--psk-file" => ret.psk_file = Some(PathBuf::from(value)),
+                _ => bail!("Unknown option {}\n{}", arg, USAGE),
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    // Defaults that keep the state in `state_path` and nothing else on disk
+    pub fn for_test(state_path: PathBuf) -> Self {
+        Config {
+            socket_path: state_path.with_extension("sock"),
+            tcp_addr: None,
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+            pairing_timeout: Duration::from_secs(1),
+            state_path,
+            log_filter: "info".to_string(),
+            log_journal: false,
+            receive_addr: None,
+            receive_as: profile::XBOX_360.name.to_string(),
+            psk_file: None,
+        }
+    }
+}
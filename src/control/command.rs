@@ -0,0 +1,232 @@
+use std::str::FromStr;
+use serde::Deserialize;
+
+use crate::{
+    control::{CommandError, ErrorCode},
+    pairing::PairingId,
+    sink::SinkId,
+};
+
+// Every request a client can make. JSON requests deserialize straight into
+// this, text lines go through `parse_text`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Hello { version: u32 },
+    ListSinks,
+    AddSink {
+        sink_type: usize,
+        #[serde(default)]
+        source: Option<String>,
+    },
+    ListSources,
+    CancelPairing { pairing: PairingId },
+    DelSink { sink: SinkId },
+    RebindSink {
+        sink: SinkId,
+        #[serde(default)]
+        source: Option<String>,
+    },
+    SinkStats { sink: SinkId },
+    SinkSettings { sink: SinkId },
+    SetSinkSetting { sink: SinkId, key: String, value: String },
+    // a sink ID or a source ID from list_sources
+    Monitor { target: String },
+    Stop,
+    ListSinkTypes,
+    Subscribe,
+    Unsubscribe,
+    Shutdown,
+    Help,
+}
+
+fn invalid(message: String) -> CommandError {
+    CommandError::new(ErrorCode::InvalidRequest, message)
+}
+
+fn number<T: FromStr>(arg: &str, what: &str) -> Result<T, CommandError> {
+    arg.parse()
+        .map_err(|_| invalid(format!("Invalid {} '{}', expected a non-negative number", what, arg)))
+}
+
+fn arity(name: &str, args: &[&str], min: usize, max: usize) -> Result<(), CommandError> {
+    if args.len() < min || args.len() > max {
+        let expected = match (min, max) {
+            (0, 0) => "no arguments".to_string(),
+            (a, b) if a == b => format!("{} argument{}", a, if a == 1 { "" } else { "s" }),
+            (a, b) => format!("{} to {} arguments", a, b),
+        };
+        return Err(invalid(format!("{} takes {}, got {}", name, expected, args.len())));
+    }
+    Ok(())
+}
+
+impl Command {
+    pub fn parse_text(line: &str) -> Result<Command, CommandError> {
+        let mut words = line.split_whitespace();
+        let name = words.next()
+            .ok_or_else(|| invalid("Empty command".to_string()))?;
+        let args: Vec<&str> = words.collect();
+
+        let (min, max) = match name {
+            "add_sink" | "rebind_sink" => (1, 2),
+            "cancel_pairing" | "del_sink" | "sink_stats" | "sink_settings" | "monitor" => (1, 1),
+            "set_sink_setting" => (3, 3),
+            "list_sinks" | "list_sources" | "list_sink_types" | "subscribe"
+                | "unsubscribe" | "stop" | "shutdown" | "help" => (0, 0),
+            _ => return Err(invalid(format!("Invalid command {}", name))),
+        };
+        arity(name, &args, min, max)?;
+
+        Ok(match name {
+            "add_sink" => Command::AddSink {
+                sink_type: number(args[0], "sink type")?,
+                source: args.get(1).map(|v| v.to_string()),
+            },
+            "cancel_pairing" => Command::CancelPairing { pairing: number(args[0], "pairing ID")? },
+            "del_sink" => Command::DelSink { sink: number(args[0], "sink ID")? },
+            "rebind_sink" => Command::RebindSink {
+                sink: number(args[0], "sink ID")?,
+                source: args.get(1).map(|v| v.to_string()),
+            },
+            "sink_stats" => Command::SinkStats { sink: number(args[0], "sink ID")? },
+            "sink_settings" => Command::SinkSettings { sink: number(args[0], "sink ID")? },
+            "set_sink_setting" => Command::SetSinkSetting {
+                sink: number(args[0], "sink ID")?,
+                key: args[1].to_string(),
+                value: args[2].to_string(),
+            },
+            "monitor" => Command::Monitor { target: args[0].to_string() },
+            "stop" => Command::Stop,
+            "list_sinks" => Command::ListSinks,
+            "list_sources" => Command::ListSources,
+            "list_sink_types" => Command::ListSinkTypes,
+            "subscribe" => Command::Subscribe,
+            "unsubscribe" => Command::Unsubscribe,
+            "shutdown" => Command::Shutdown,
+            "help" => Command::Help,
+            _ => unreachable!("checked above"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn error(line: &str) -> String {
+        Command::parse_text(line).unwrap_err().message
+    }
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!(Command::parse_text("list_sinks").unwrap(), Command::ListSinks);
+        assert_eq!(Command::parse_text("list_sources").unwrap(), Command::ListSources);
+        assert_eq!(Command::parse_text("list_sink_types").unwrap(), Command::ListSinkTypes);
+        assert_eq!(Command::parse_text("subscribe").unwrap(), Command::Subscribe);
+        assert_eq!(Command::parse_text("unsubscribe").unwrap(), Command::Unsubscribe);
+        assert_eq!(Command::parse_text("shutdown").unwrap(), Command::Shutdown);
+        assert_eq!(Command::parse_text("help").unwrap(), Command::Help);
+        assert_eq!(Command::parse_text("add_sink 0").unwrap(),
+            Command::AddSink { sink_type: 0, source: None });
+        assert_eq!(Command::parse_text("add_sink 1 event5").unwrap(),
+            Command::AddSink { sink_type: 1, source: Some("event5".to_string()) });
+        assert_eq!(Command::parse_text("cancel_pairing 3").unwrap(), Command::CancelPairing { pairing: 3 });
+        assert_eq!(Command::parse_text("del_sink 7").unwrap(), Command::DelSink { sink: 7 });
+        assert_eq!(Command::parse_text("rebind_sink 7").unwrap(), Command::RebindSink { sink: 7, source: None });
+        assert_eq!(Command::parse_text("rebind_sink 7 event2").unwrap(),
+            Command::RebindSink { sink: 7, source: Some("event2".to_string()) });
+        assert_eq!(Command::parse_text("sink_stats 2").unwrap(), Command::SinkStats { sink: 2 });
+        assert_eq!(Command::parse_text("sink_settings 2").unwrap(), Command::SinkSettings { sink: 2 });
+        assert_eq!(Command::parse_text("set_sink_setting 2 rumble off").unwrap(),
+            Command::SetSinkSetting { sink: 2, key: "rumble".to_string(), value: "off".to_string() });
+        assert_eq!(Command::parse_text("monitor event4").unwrap(), Command::Monitor { target: "event4".to_string() });
+        assert_eq!(Command::parse_text("stop").unwrap(), Command::Stop);
+    }
+
+    #[test]
+    fn tolerates_extra_whitespace() {
+        assert_eq!(Command::parse_text("  del_sink\t 7 \r").unwrap(), Command::DelSink { sink: 7 });
+    }
+
+    #[test]
+    fn rejects_missing_arguments() {
+        assert_eq!(error("add_sink"), "add_sink takes 1 to 2 arguments, got 0");
+        assert_eq!(error("del_sink"), "del_sink takes 1 argument, got 0");
+        assert_eq!(error("cancel_pairing"), "cancel_pairing takes 1 argument, got 0");
+        assert_eq!(error("sink_stats"), "sink_stats takes 1 argument, got 0");
+        assert_eq!(error("set_sink_setting 2 rumble"), "set_sink_setting takes 3 arguments, got 2");
+    }
+
+    #[test]
+    fn rejects_extra_arguments() {
+        assert_eq!(error("list_sinks now"), "list_sinks takes no arguments, got 1");
+        assert_eq!(error("add_sink 0 event1 event2"), "add_sink takes 1 to 2 arguments, got 3");
+    }
+
+    #[test]
+    fn rejects_bad_numbers() {
+        assert_eq!(error("del_sink -1"), "Invalid sink ID '-1', expected a non-negative number");
+        assert_eq!(error("add_sink pad"), "Invalid sink type 'pad', expected a non-negative number");
+        assert_eq!(error("cancel_pairing 99999999999999999999"),
+            "Invalid pairing ID '99999999999999999999', expected a non-negative number");
+    }
+
+    #[test]
+    fn rejects_unknown_and_empty() {
+        assert_eq!(error(""), "Empty command");
+        assert_eq!(error("   "), "Empty command");
+        assert_eq!(error("hello 1"), "Invalid command hello");
+        assert_eq!(Command::parse_text("frobnicate").unwrap_err().code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn json_matches_text() {
+        let json: Command = serde_json::from_str(r#"{"cmd":"add_sink","sink_type":2,"source":"event3"}"#).unwrap();
+        assert_eq!(json, Command::parse_text("add_sink 2 event3").unwrap());
+        let json: Command = serde_json::from_str(r#"{"cmd":"del_sink","sink":4}"#).unwrap();
+        assert_eq!(json, Command::parse_text("del_sink 4").unwrap());
+        let json: Command = serde_json::from_str(r#"{"cmd":"rebind_sink","sink":4}"#).unwrap();
+        assert_eq!(json, Command::parse_text("rebind_sink 4").unwrap());
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics(line in "\\PC*") {
+            let _ = Command::parse_text(&line);
+        }
+
+        #[test]
+        fn never_panics_on_known_names(
+            name in prop::sample::select(vec!["add_sink", "cancel_pairing", "del_sink", "sink_stats", "list_sinks", "help"]),
+            args in prop::collection::vec("\\PC{0,8}", 0..4),
+        ) {
+            let _ = Command::parse_text(&format!("{} {}", name, args.join(" ")));
+        }
+
+        #[test]
+        fn round_trips_numbers(sink_type: usize, sink: SinkId, pairing: PairingId) {
+            prop_assert_eq!(Command::parse_text(&format!("add_sink {}", sink_type)).unwrap(),
+                Command::AddSink { sink_type, source: None });
+            prop_assert_eq!(Command::parse_text(&format!("del_sink {}", sink)).unwrap(),
+                Command::DelSink { sink });
+            prop_assert_eq!(Command::parse_text(&format!("cancel_pairing {}", pairing)).unwrap(),
+                Command::CancelPairing { pairing });
+        }
+
+        #[test]
+        fn keeps_source_ids(source in "[a-z0-9+]{1,16}") {
+            prop_assert_eq!(Command::parse_text(&format!("add_sink 0 {}", source)).unwrap(),
+                Command::AddSink { sink_type: 0, source: Some(source) });
+        }
+
+        #[test]
+        fn errors_are_descriptive(line in "\\PC*") {
+            if let Err(e) = Command::parse_text(&line) {
+                prop_assert!(!e.message.is_empty());
+                prop_assert_eq!(e.code, ErrorCode::InvalidRequest);
+            }
+        }
+    }
+}
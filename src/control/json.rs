@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use log::debug;
+
+use crate::{
+    control::{
+        command::Command,
+        CommandError,
+        ErrorCode,
+        Session,
+        SinkInfo,
+        SinkTypeInfo,
+        SourceInfo,
+        HELP_TEXT,
+    },
+    pairing::PairingId,
+    sink::{stats::StatsSnapshot, SinkId},
+};
+
+// Version of the JSON-lines protocol spoken by this daemon
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// A request line, `id` is echoed back untouched so clients can match replies
+#[derive(Deserialize, Debug)]
+struct Envelope {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(flatten)]
+    request: Command,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Reply {
+    Hello { version: u32, daemon: String },
+    Done,
+    Pairing { pairing: PairingId },
+    SinkAdded { sink: SinkId },
+    Sources { sources: Vec<SourceInfo> },
+    Sinks { sinks: Vec<SinkInfo> },
+    SinkTypes { sink_types: Vec<SinkTypeInfo> },
+    SinkStats { stats: StatsSnapshot },
+    SinkSettings { settings: BTreeMap<String, String> },
+    Help { text: String },
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pairing: Option<PairingId>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Reply>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+impl Response {
+    fn new(id: Option<Value>, reply: Result<Reply, CommandError>) -> Self {
+        match reply {
+            Ok(r) => Response { id, pairing: None, ok: true, result: Some(r), error: None },
+            Err(e) => Response {
+                id,
+                pairing: None,
+                ok: false,
+                result: None,
+                error: Some(ErrorBody { code: e.code, message: e.message }),
+            },
+        }
+    }
+}
+
+fn execute(session: &mut Session, request: Command) -> Result<Reply, CommandError> {
+    if let Command::Hello { version } = request {
+        if version == 0 || version > PROTOCOL_VERSION {
+            return Err(CommandError::new(ErrorCode::UnsupportedVersion,
+                format!("Protocol version {} is not supported, newest is {}", version, PROTOCOL_VERSION)));
+        }
+        session.json_version = Some(version);
+        return Ok(Reply::Hello {
+            version,
+            daemon: format!("rinputer4 {}", env!("CARGO_PKG_VERSION")),
+        });
+    }
+
+    if session.json_version.is_none() {
+        return Err(CommandError::new(ErrorCode::HandshakeRequired,
+            "Send a hello command before any other JSON command"));
+    }
+
+    match request {
+        Command::Hello { .. } => unreachable!(),
+        Command::ListSinks => Ok(Reply::Sinks { sinks: session.list_sinks() }),
+        Command::AddSink { sink_type, source: Some(source) } => session.bind_sink(sink_type, &source)
+            .map(|sink| Reply::SinkAdded { sink }),
+        Command::AddSink { sink_type, source: None } => session.add_sink(sink_type, true)
+            .map(|pairing| Reply::Pairing { pairing }),
+        Command::RebindSink { sink, source: Some(source) } => session.rebind_to(sink, &source)
+            .map(|sink| Reply::SinkAdded { sink }),
+        Command::RebindSink { sink, source: None } => session.pair_rebind(sink, true)
+            .map(|pairing| Reply::Pairing { pairing }),
+        Command::ListSources => Ok(Reply::Sources { sources: session.list_sources() }),
+        Command::CancelPairing { pairing } => session.cancel_pairing(pairing).map(|_| Reply::Done),
+        Command::DelSink { sink } => session.del_sink(sink).map(|_| Reply::Done),
+        Command::SinkStats { sink } => session.sink_stats(sink).map(|stats| Reply::SinkStats { stats }),
+        Command::SinkSettings { sink } => session.sink_settings(sink)
+            .map(|settings| Reply::SinkSettings { settings }),
+        Command::SetSinkSetting { sink, key, value } => session.set_sink_setting(sink, &key, &value)
+            .map(|_| Reply::Done),
+        Command::Monitor { target } => session.monitor(&target, true).map(|_| Reply::Done),
+        Command::Stop => session.stop_monitor().map(|_| Reply::Done),
+        Command::ListSinkTypes => Ok(Reply::SinkTypes { sink_types: session.list_sink_types() }),
+        Command::Subscribe => {
+            session.subscribe(true);
+            Ok(Reply::Done)
+        },
+        Command::Unsubscribe => {
+            session.unsubscribe();
+            Ok(Reply::Done)
+        },
+        Command::Shutdown => {
+            session.shutdown();
+            Ok(Reply::Done)
+        },
+        Command::Help => Ok(Reply::Help { text: String::from_utf8_lossy(HELP_TEXT).into_owned() }),
+    }
+}
+
+fn to_line(response: &Response) -> String {
+    let mut out = serde_json::to_string(response)
+        .expect("responses are always serializable");
+    out.push('\n');
+    out
+}
+
+// Late reply to add_sink, tagged with the pairing ID instead of a request ID
+pub fn pairing_line(pairing: PairingId, result: Result<SinkId, CommandError>) -> String {
+    let mut response = Response::new(None, result.map(|sink| Reply::SinkAdded { sink }));
+    response.pairing = Some(pairing);
+    to_line(&response)
+}
+
+// Reply to a request line that couldn't even be read
+pub fn invalid_line(reason: &str) -> String {
+    to_line(&Response::new(None, Err(CommandError::new(ErrorCode::InvalidRequest, reason.to_string()))))
+}
+
+// Handles one JSON request line and returns the serialized response line
+pub fn handle_line(session: &mut Session, line: &str) -> String {
+    let response = match serde_json::from_str::<Envelope>(line) {
+        Ok(envelope) => {
+            debug!("{:?}", envelope.request);
+            Response::new(envelope.id, execute(session, envelope.request))
+        },
+        Err(e) => {
+            debug!("Rejected {:?}: {}", line, e);
+            // still try to echo the id back if the line was at least an object
+            let id = serde_json::from_str::<Value>(line).ok()
+                .and_then(|v| v.get("id").cloned());
+            Response::new(id, Err(CommandError::new(ErrorCode::InvalidRequest, e.to_string())))
+        },
+    };
+
+    to_line(&response)
+}
@@ -0,0 +1,141 @@
+use std::{
+    ffi::CString,
+    fs::{self, Permissions},
+    net::TcpListener,
+    os::unix::{
+        fs::PermissionsExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::{self, Gid, Uid, User},
+};
+use anyhow::{bail, Result};
+use log::warn;
+
+use crate::{
+    control::handle_client,
+    daemon::Daemon,
+    source::{StopFlag, POLL_INTERVAL},
+};
+
+// Decides which local users may talk to the daemon, based on SO_PEERCRED.
+// root and the user the daemon runs as are always allowed.
+pub struct AccessPolicy {
+    uids: Vec<Uid>,
+    gids: Vec<Gid>,
+}
+
+impl AccessPolicy {
+    pub fn new(uids: Vec<Uid>, gids: Vec<Gid>) -> Self {
+        Self { uids, gids }
+    }
+
+    fn allows_user(&self, uid: Uid, gid: Gid) -> Result<bool> {
+        if uid.is_root() || uid == unistd::geteuid() || self.uids.contains(&uid) {
+            return Ok(true);
+        }
+        if self.gids.contains(&gid) {
+            return Ok(true);
+        }
+        if self.gids.is_empty() {
+            return Ok(false);
+        }
+
+        // SO_PEERCRED only carries the primary group, look up the rest
+        if let Some(user) = User::from_uid(uid)? {
+            let name = CString::new(user.name)?;
+            let groups = unistd::getgrouplist(&name, user.gid)?;
+            return Ok(groups.iter().any(|g| self.gids.contains(g)));
+        }
+
+        Ok(false)
+    }
+
+    pub fn allows(&self, stream: &UnixStream) -> Result<bool> {
+        let cred = getsockopt(stream.as_raw_fd(), PeerCredentials)?;
+        self.allows_user(Uid::from_raw(cred.uid()), Gid::from_raw(cred.gid()))
+    }
+}
+
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("Another instance is already listening on {}", path.display());
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // everyone may connect, AccessPolicy decides who gets served
+    fs::set_permissions(path, Permissions::from_mode(0o666))?;
+
+    Ok(listener)
+}
+
+// Waits until a client is knocking, false once `stop` is set
+pub fn wait_for_client(listener: &impl AsRawFd, stop: &StopFlag) -> bool {
+    let mut fds = [PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN)];
+    while !stop.is_stopped() {
+        match poll(&mut fds, POLL_INTERVAL.as_millis() as i32) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => return true,
+            Err(e) => {
+                warn!("Can't wait for clients: {}", e);
+                return false;
+            },
+        }
+    }
+    false
+}
+
+pub fn serve_unix(listener: UnixListener, policy: AccessPolicy, daemon: Arc<Daemon>) {
+    let policy = Arc::new(policy);
+    while wait_for_client(&listener, &daemon.stopping) {
+        let mut stream = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(e) => {
+                warn!("Failed accepting a client: {}", e);
+                continue;
+            },
+        };
+
+        match policy.allows(&stream) {
+            Ok(true) => (),
+            Ok(false) => {
+                let _ = stream.write_all(b"ERR:Permission denied\n");
+                continue;
+            },
+            Err(e) => {
+                warn!("Failed checking client credentials: {}", e);
+                continue;
+            },
+        }
+
+        let ptr = Arc::clone(&daemon);
+        std::thread::spawn(move || handle_client(stream, ptr));
+    }
+}
+
+pub fn serve_tcp(listener: TcpListener, daemon: Arc<Daemon>) {
+    while wait_for_client(&listener, &daemon.stopping) {
+        match listener.accept() {
+            Ok((s, _)) => {
+                let ptr = Arc::clone(&daemon);
+                std::thread::spawn(move || handle_client(s, ptr));
+            },
+            Err(e) => warn!("Failed accepting a client: {}", e),
+        }
+    }
+}
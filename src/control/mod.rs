@@ -0,0 +1,806 @@
+pub mod listener;
+pub mod json;
+pub mod command;
+
+use std::collections::BTreeMap;
+use std::io::{
+    self,
+    BufReader,
+    BufRead,
+    Read,
+    Write,
+};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::{
+    mpsc,
+    Arc,
+    Mutex,
+    Weak,
+};
+use serde::Serialize;
+use anyhow::Result;
+use log::{debug, error};
+
+use crate::{
+    control::command::Command,
+    daemon::Daemon,
+    events::{self, Event, Subscription},
+    monitor::{self, Monitor, MonitorEvent, Stage},
+    pairing::{PairingError, PairingId, PairingTicket},
+    persist::State,
+    sink::{self, stats::StatsSnapshot, NewSinkFn, SinkId, SinkTable},
+    source::{self, OpenedEventSource, SourceCaps},
+};
+
+static HELP_TEXT: &[u8] = b"Available commands are:
+list_sinks: Lists all sinks in use with sources attached to them, their player numbers and source caps
+add_sink: Adds a sink and autobinds a source, replies with a pairing ID and later with a PAIRING line
+add_sink <type> <source>: Adds a sink bound to a source from list_sources, replies with the sink
+list_sources: Lists input devices that can be bound to sinks
+cancel_pairing: Gives up on a pending add_sink
+del_sink: Removes a sink by the ID shown in list_sinks
+rebind_sink <sink> [source]: Feeds a sink from another source, autobound like add_sink if none is given, keeping its virtual device
+sink_stats <sink>: Shows events forwarded, events/s, ms since the last event, queue depth, average/max latency in us, frames forwarded and writes per frame
+sink_settings <sink>: Lists the settings of a sink as key:value lines
+set_sink_setting <sink> <key> <value>: Changes a setting, e.g. rumble on|off, rumble_intensity 0-100 or motion on|off, or peer host:port and transport udp|tcp of a Network sink
+list_sink_types: Lists sink types that can be added with add_sink
+monitor <sink-or-source>: Streams MONITOR:<target>:<raw|output>:<time>:<type>:<code>:<value> lines until stop
+stop: Ends a running monitor
+subscribe: Pushes EVENT lines about sources and sinks appearing or going away
+unsubscribe: Stops pushing EVENT lines
+shutdown: Removes all sinks, releases their sources and stops the daemon
+help: Displays this message
+Fields are separated by ':', a '%', ':' or newline within a field is sent as %25, %3A or %0A
+Sending {\"cmd\":\"hello\",\"version\":1} switches to the JSON-lines protocol
+";
+
+// Makes free text like device names safe to put in a text protocol line
+pub fn escape_field(field: &str) -> String {
+    field.replace('%', "%25")
+        .replace(':', "%3A")
+        .replace('\n', "%0A")
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    HandshakeRequired,
+    UnsupportedVersion,
+    NoSuchSinkType,
+    NoSuchSink,
+    NoSuchSource,
+    SourceBusy,
+    SinkCreationFailed,
+    NoSuchPairing,
+    InvalidSetting,
+    RebindFailed,
+    PairingCancelled,
+    PairingTimedOut,
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl From<PairingError> for CommandError {
+    fn from(e: PairingError) -> Self {
+        match e {
+            PairingError::Cancelled => CommandError::new(ErrorCode::PairingCancelled, "Pairing was cancelled"),
+            PairingError::TimedOut => CommandError::new(ErrorCode::PairingTimedOut, "Nobody pressed L+R in time"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SinkInfo {
+    pub id: SinkId,
+    pub name: String,
+    pub source: String,
+    pub caps: SourceCaps,
+    pub player: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SourceInfo {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+    pub vendor: u16,
+    pub product: u16,
+    pub uniq: String,
+    pub caps: SourceCaps,
+    // sink this source is bound to, if any
+    pub sink: Option<SinkId>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SinkTypeInfo {
+    pub id: usize,
+    pub name: String,
+}
+
+pub trait ControlStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl ControlStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+impl ControlStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+fn forward_events<T: Serialize>(writer: Arc<Mutex<dyn Write + Send>>, rx: mpsc::Receiver<T>, json: bool,
+    to_text: fn(&T) -> String)
+{
+    for ev in rx {
+        let line = if json {
+            let mut tmp = serde_json::to_string(&ev).expect("events are always serializable");
+            tmp.push('\n');
+            tmp
+        } else {
+            to_text(&ev)
+        };
+
+        if writer.lock().unwrap().write_all(line.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// State of one client connection, shared by the text and JSON protocols
+pub struct Session {
+    sink_types: Vec<(String, NewSinkFn)>,
+    daemon: Arc<Daemon>,
+    json_version: Option<u32>,
+    writer: Arc<Mutex<dyn Write + Send>>,
+    subscription: Option<Subscription>,
+    monitor: Option<Monitoring>,
+    pairings: Vec<PairingId>,
+}
+
+// A running monitor, and for a sink what tells it the sink was rebound
+struct Monitoring {
+    _monitor: Arc<Mutex<Monitor>>,
+    _rebinds: Option<Subscription>,
+}
+
+// Both what the devices of a sink's sources send and what the sink gets
+fn tap_sources(monitor: &mut Monitor, ids: &str) {
+    for id in ids.split('+') {
+        monitor.tap(Stage::Raw, id);
+    }
+    monitor.tap(Stage::Output, ids);
+}
+
+// Moves the taps of a sink's monitor over to every source the sink is
+// rebound to, until the monitor or the subscription is gone
+fn follow_sink(daemon: &Daemon, sink: SinkId, monitor: Weak<Mutex<Monitor>>, rx: mpsc::Receiver<Event>) {
+    for ev in rx {
+        if !matches!(ev, Event::SinkRebound { sink: id, .. } if id == sink) {
+            continue;
+        }
+        let monitor = match monitor.upgrade() {
+            Some(m) => m,
+            None => return,
+        };
+        let ids = daemon.sinks.lock().unwrap()
+            .iter()
+            .find(|(id, _)| *id == sink)
+            .map(|(_, s)| s.source_id());
+        if let Some(ids) = ids {
+            let mut monitor = monitor.lock().unwrap();
+            monitor.untap();
+            tap_sources(&mut monitor, &ids);
+        }
+    }
+}
+
+// Waits for someone to press L+R on a source no sink uses
+fn pair_source(daemon: &Daemon, ticket: &PairingTicket) -> Result<OpenedEventSource, CommandError> {
+    // only lock for a moment on every rescan, other clients keep working
+    let is_bound = |id: &str| daemon.sinks.lock().unwrap()
+        .iter()
+        .any(|(_, sink)| sink.source_id().split('+').any(|v| v == id));
+    Ok(ticket.wait_for_source(is_bound)?)
+}
+
+// Waits for a source and turns it into a sink, returns the new sink's ID
+fn pair_sink(daemon: &Daemon, new_fn: NewSinkFn, ticket: &PairingTicket) -> Result<SinkId, CommandError> {
+    let new_source = pair_source(daemon, ticket)?;
+    let mut all_sinks = daemon.sinks.lock().unwrap();
+    insert_sink(&daemon.state, &mut all_sinks, new_fn, new_source)
+}
+
+fn rebind_sink(daemon: &Daemon, sink: SinkId, new_source: OpenedEventSource) -> Result<SinkId, CommandError> {
+    let rebound = daemon.sinks.lock().unwrap()
+        .rebind(sink, new_source)
+        .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSink, format!("There is no sink {}", sink)))?
+        .map_err(|e| CommandError::new(ErrorCode::RebindFailed, e.to_string()))?;
+    // other clients keep working while the sink switches over
+    rebound.wait()
+        .map_err(|e| CommandError::new(ErrorCode::RebindFailed, e.to_string()))?;
+
+    // the sink may have been deleted meanwhile
+    let all_sinks = daemon.sinks.lock().unwrap();
+    let source = all_sinks.iter()
+        .find(|(id, _)| *id == sink)
+        .map(|(_, s)| s.source_name())
+        .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSink, format!("Sink {} went away", sink)))?;
+    all_sinks.rebound(sink);
+    events::publish(Event::SinkRebound { sink, source });
+    daemon.state.save(&all_sinks);
+    Ok(sink)
+}
+
+fn insert_sink(state: &State, all_sinks: &mut SinkTable, new_fn: NewSinkFn, new_source: OpenedEventSource) -> Result<SinkId, CommandError> {
+    match new_fn(new_source) {
+        Ok(sink) => {
+            let name = sink.name().to_string();
+            let source = sink.source_name();
+            let id = all_sinks.insert(sink)
+                .map_err(|e| CommandError::new(ErrorCode::SinkCreationFailed, e.to_string()))?;
+            events::publish(Event::SinkCreated { sink: id, name, source });
+            state.save(all_sinks);
+            Ok(id)
+        }
+        Err(e) => {
+            error!("Failed making a new sink: {}", e);
+            Err(CommandError::new(ErrorCode::SinkCreationFailed, e.to_string()))
+        }
+    }
+}
+
+fn open_source(source_id: &str) -> Result<OpenedEventSource, CommandError> {
+    source::open_by_id(source_id)
+        .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSource,
+                format!("There is no source {}", source_id)))?
+        .map_err(|e| CommandError::new(ErrorCode::SourceBusy,
+                format!("Can't grab source {}: {}", source_id, e)))
+}
+
+fn pairing_text(pairing: PairingId, result: &Result<SinkId, CommandError>) -> String {
+    match result {
+        Ok(sink) => format!("PAIRING:{}:OK:{}\n", pairing, sink),
+        Err(e) => format!("PAIRING:{}:ERR:{}\n", pairing, e.message),
+    }
+}
+
+impl Session {
+    fn new(daemon: Arc<Daemon>, writer: Arc<Mutex<dyn Write + Send>>) -> Self {
+        Self {
+            sink_types: sink::list_names(),
+            daemon,
+            json_version: None,
+            writer,
+            subscription: None,
+            monitor: None,
+            pairings: Vec::new(),
+        }
+    }
+
+    fn subscribe(&mut self, json: bool) {
+        let (subscription, rx) = events::subscribe();
+        // replacing an older subscription ends its forwarding thread
+        self.subscription = Some(subscription);
+
+        let writer = Arc::clone(&self.writer);
+        std::thread::spawn(move || forward_events(writer, rx, json, Event::to_text));
+    }
+
+    fn unsubscribe(&mut self) {
+        self.subscription = None;
+    }
+
+    // Streams events of a sink, or of a source by ID, until stop_monitor.
+    // Both what the device sends and what comes out of the source's
+    // processing are shown.
+    fn monitor(&mut self, target: &str, json: bool) -> Result<(), CommandError> {
+        let (mut monitor, rx) = Monitor::new();
+        // taken before looking up the sink, so no rebind goes unnoticed
+        let (rebinds, rebinds_rx) = events::subscribe();
+
+        let (by_sink, bound) = {
+            let all_sinks = self.daemon.sinks.lock().unwrap();
+            let by_sink = target.parse::<SinkId>().ok()
+                .and_then(|id| all_sinks.iter().find(|(sink, _)| *sink == id))
+                .map(|(id, s)| (id, s.source_id()));
+            let by_source = all_sinks.iter()
+                .map(|(_, s)| s.source_id())
+                .find(|ids| ids.split('+').any(|v| v == target));
+            match by_sink {
+                Some((id, ids)) => (Some(id), Some(ids)),
+                None => (None, by_source),
+            }
+        };
+
+        match bound {
+            Some(ids) => tap_sources(&mut monitor, &ids),
+            None => {
+                // nothing reads an unbound source, so read it without grabbing
+                let src = source::open_shared(target)
+                    .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSource,
+                            format!("There is no sink or source {}", target)))?
+                    .map_err(|e| CommandError::new(ErrorCode::NoSuchSource,
+                            format!("Can't open source {}: {}", target, e)))?;
+                monitor.tap(Stage::Raw, target);
+                monitor.tap(Stage::Output, target);
+
+                let stop = monitor.stop_flag();
+                std::thread::spawn(move || {
+                    while let Some(ev) = src.recv_until(&stop) {
+                        monitor::publish(Stage::Output, &src.id, &ev);
+                    }
+                });
+            },
+        }
+
+        // a sink keeps its ID when rebound, so its monitor moves along
+        let monitor = Arc::new(Mutex::new(monitor));
+        let rebinds = by_sink.map(|sink| {
+            let (daemon, weak) = (Arc::clone(&self.daemon), Arc::downgrade(&monitor));
+            std::thread::spawn(move || follow_sink(&daemon, sink, weak, rebinds_rx));
+            rebinds
+        });
+
+        // replacing an older monitor ends its forwarding thread
+        self.monitor = Some(Monitoring { _monitor: monitor, _rebinds: rebinds });
+        let writer = Arc::clone(&self.writer);
+        std::thread::spawn(move || forward_events(writer, rx, json, MonitorEvent::to_text));
+        Ok(())
+    }
+
+    fn stop_monitor(&mut self) -> Result<(), CommandError> {
+        self.monitor.take()
+            .map(|_| ())
+            .ok_or_else(|| CommandError::new(ErrorCode::InvalidRequest, "No monitor is running"))
+    }
+
+    // Runs `job` with a pairing in the background, its outcome is written
+    // to this connection as soon as it is known
+    fn start_pairing(&mut self, json: bool,
+        job: impl FnOnce(&Daemon, &PairingTicket) -> Result<SinkId, CommandError> + Send + 'static) -> PairingId
+    {
+        let ticket = self.daemon.pairings.start();
+        let pairing = ticket.id();
+        self.pairings.push(pairing);
+
+        let daemon = Arc::clone(&self.daemon);
+        let writer = Arc::clone(&self.writer);
+        std::thread::spawn(move || {
+            let result = job(&daemon, &ticket);
+            drop(ticket);
+
+            let line = if json {
+                json::pairing_line(pairing, result)
+            } else {
+                pairing_text(pairing, &result)
+            };
+            let _ = writer.lock().unwrap().write_all(line.as_bytes());
+        });
+
+        pairing
+    }
+
+    // Starts pairing for a new sink, see start_pairing
+    fn add_sink(&mut self, snk_type: usize, json: bool) -> Result<PairingId, CommandError> {
+        let (_, new_fn) = self.sink_types.get(snk_type)
+            .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSinkType,
+                    format!("There is no sink type {}", snk_type)))?;
+        let new_fn = *new_fn;
+        Ok(self.start_pairing(json, move |daemon, ticket| pair_sink(daemon, new_fn, ticket)))
+    }
+
+    // Starts pairing for a new source of an existing sink, see start_pairing
+    fn pair_rebind(&mut self, sink: SinkId, json: bool) -> Result<PairingId, CommandError> {
+        if !self.daemon.sinks.lock().unwrap().iter().any(|(id, _)| id == sink) {
+            return Err(CommandError::new(ErrorCode::NoSuchSink, format!("There is no sink {}", sink)));
+        }
+        Ok(self.start_pairing(json, move |daemon, ticket| {
+            let new_source = pair_source(daemon, ticket)?;
+            rebind_sink(daemon, sink, new_source)
+        }))
+    }
+
+    // Swaps in a specific source right away, no gesture needed
+    fn rebind_to(&self, sink: SinkId, source_id: &str) -> Result<SinkId, CommandError> {
+        let new_source = open_source(source_id)?;
+        rebind_sink(&self.daemon, sink, new_source)
+    }
+
+    // Binds a specific source right away, no gesture needed
+    fn bind_sink(&self, snk_type: usize, source_id: &str) -> Result<SinkId, CommandError> {
+        let (_, new_fn) = self.sink_types.get(snk_type)
+            .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSinkType,
+                    format!("There is no sink type {}", snk_type)))?;
+
+        let new_source = open_source(source_id)?;
+
+        let mut all_sinks = self.daemon.sinks.lock().unwrap();
+        insert_sink(&self.daemon.state, &mut all_sinks, *new_fn, new_source)
+    }
+
+    fn list_sources(&self) -> Vec<SourceInfo> {
+        let all_sinks = self.daemon.sinks.lock().unwrap();
+        source::enumerate().into_iter()
+            .map(|src| {
+                let id = src.id();
+                let sink = all_sinks.iter()
+                    .find(|(_, s)| s.source_id().split('+').any(|v| v == id))
+                    .map(|(sink_id, _)| sink_id);
+                SourceInfo {
+                    name: src.name(),
+                    path: src.path(),
+                    vendor: src.input_id().vendor(),
+                    product: src.input_id().product(),
+                    uniq: src.uniq(),
+                    caps: src.get_capabilities(),
+                    sink,
+                    id,
+                }
+            })
+            .collect()
+    }
+
+    fn cancel_pairing(&self, pairing: PairingId) -> Result<(), CommandError> {
+        if self.daemon.pairings.cancel(pairing) {
+            Ok(())
+        } else {
+            Err(CommandError::new(ErrorCode::NoSuchPairing,
+                    format!("There is no pending pairing {}", pairing)))
+        }
+    }
+
+    fn del_sink(&self, victim: SinkId) -> Result<(), CommandError> {
+        let mut all_sinks = self.daemon.sinks.lock().unwrap();
+        if all_sinks.remove(victim).is_none() {
+            return Err(CommandError::new(ErrorCode::NoSuchSink,
+                    format!("There is no sink {}", victim)));
+        }
+        events::publish(Event::SinkDeleted { sink: victim });
+        self.daemon.state.save(&all_sinks);
+        Ok(())
+    }
+
+    fn sink_stats(&self, sink: SinkId) -> Result<StatsSnapshot, CommandError> {
+        self.daemon.sinks.lock().unwrap()
+            .iter()
+            .find(|(id, _)| *id == sink)
+            .map(|(_, s)| s.stats())
+            .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSink,
+                    format!("There is no sink {}", sink)))
+    }
+
+    fn sink_settings(&self, sink: SinkId) -> Result<BTreeMap<String, String>, CommandError> {
+        self.daemon.sinks.lock().unwrap()
+            .iter()
+            .find(|(id, _)| *id == sink)
+            .map(|(_, s)| s.settings())
+            .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSink,
+                    format!("There is no sink {}", sink)))
+    }
+
+    fn set_sink_setting(&self, sink: SinkId, key: &str, value: &str) -> Result<(), CommandError> {
+        let mut all_sinks = self.daemon.sinks.lock().unwrap();
+        all_sinks.get_mut(sink)
+            .ok_or_else(|| CommandError::new(ErrorCode::NoSuchSink,
+                    format!("There is no sink {}", sink)))?
+            .set_setting(key, value)
+            .map_err(|e| CommandError::new(ErrorCode::InvalidSetting, e.to_string()))?;
+        self.daemon.state.save(&all_sinks);
+        Ok(())
+    }
+
+    fn list_sink_types(&self) -> Vec<SinkTypeInfo> {
+        self.sink_types.iter()
+            .enumerate()
+            .map(|(id, (name, _))| SinkTypeInfo { id, name: name.clone() })
+            .collect()
+    }
+
+    fn list_sinks(&self) -> Vec<SinkInfo> {
+        let all_sinks = self.daemon.sinks.lock().unwrap();
+        all_sinks.iter()
+            .map(|(id, sink)| SinkInfo {
+                id,
+                name: sink.name().to_string(),
+                source: sink.source_name(),
+                caps: sink.source_caps(),
+                player: all_sinks.player(id).unwrap_or(0),
+            })
+            .collect()
+    }
+
+    fn shutdown(&self) {
+        self.daemon.request_shutdown();
+    }
+}
+
+impl Drop for Session {
+    // nobody is left to report to, so give up on pairings of this connection
+    fn drop(&mut self) {
+        for pairing in self.pairings.iter() {
+            self.daemon.pairings.cancel(*pairing);
+        }
+    }
+}
+
+fn write_err<S: Write>(stream: &mut S, e: &CommandError) -> io::Result<()> {
+    stream.write_all(format!("ERR:{}\n", e.message).as_bytes())
+}
+
+fn handle_text<S: Write>(stream: &mut S, session: &mut Session, line: &str) -> Result<()> {
+    let command = match Command::parse_text(line) {
+        Ok(c) => c,
+        Err(e) => {
+            debug!("Rejected {:?}: {}", line, e.message);
+            return Ok(write_err(stream, &e)?);
+        },
+    };
+    debug!("{:?}", command);
+
+    match &command {
+        Command::AddSink { sink_type, source: Some(source) } => {
+            match session.bind_sink(*sink_type, source) {
+                Ok(sink) => stream.write_all(format!("OK:{}\n", sink).as_bytes())?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::AddSink { sink_type, source: None } => {
+            match session.add_sink(*sink_type, false) {
+                Ok(pairing) => stream.write_all(format!("OK:{}\n", pairing).as_bytes())?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::RebindSink { sink, source: Some(source) } => {
+            match session.rebind_to(*sink, source) {
+                Ok(sink) => stream.write_all(format!("OK:{}\n", sink).as_bytes())?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::RebindSink { sink, source: None } => {
+            match session.pair_rebind(*sink, false) {
+                Ok(pairing) => stream.write_all(format!("OK:{}\n", pairing).as_bytes())?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::ListSources => {
+            for info in session.list_sources() {
+                let response = format!("OK:{}:{}:{}:{:04x}:{:04x}:{}:{}:{}\n",
+                    info.id, escape_field(&info.name), escape_field(&info.path), info.vendor, info.product,
+                    escape_field(&info.uniq), info.caps.as_str(),
+                    info.sink.map_or("-".to_string(), |v| v.to_string()));
+                stream.write_all(response.as_bytes())?;
+            }
+            stream.write_all(b"END_MULTILINE\n")?;
+        },
+        Command::CancelPairing { pairing } => {
+            match session.cancel_pairing(*pairing) {
+                Ok(()) => stream.write_all(b"OK\n")?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::DelSink { sink } => {
+            match session.del_sink(*sink) {
+                Ok(()) => stream.write_all(b"OK\n")?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        }
+        Command::SinkStats { sink } => {
+            match session.sink_stats(*sink) {
+                Ok(st) => {
+                    let opt = |v: Option<u64>| v.map_or("-".to_string(), |v| v.to_string());
+                    let per_frame = st.writes_per_frame.map_or("-".to_string(), |v| format!("{:.2}", v));
+                    let response = format!("OK:{}:{:.1}:{}:{}:{}:{}:{}:{}\n",
+                        st.events_forwarded, st.events_per_second, opt(st.last_event_ms_ago),
+                        st.queue_depth, opt(st.latency_avg_us), opt(st.latency_max_us),
+                        st.frames_forwarded, per_frame);
+                    stream.write_all(response.as_bytes())?;
+                },
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::SinkSettings { sink } => {
+            match session.sink_settings(*sink) {
+                Ok(settings) => {
+                    for (key, value) in settings {
+                        stream.write_all(format!("OK:{}:{}\n", key, value).as_bytes())?;
+                    }
+                    stream.write_all(b"END_MULTILINE\n")?;
+                },
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::SetSinkSetting { sink, key, value } => {
+            match session.set_sink_setting(*sink, key, value) {
+                Ok(()) => stream.write_all(b"OK\n")?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::ListSinkTypes => {
+            for info in session.list_sink_types() {
+                let tmp = format!("OK:{}:{}\n", info.id, info.name);
+                stream.write_all(tmp.as_bytes())?;
+            }
+            stream.write_all(b"END_MULTILINE\n")?;
+        },
+        Command::ListSinks => {
+            for info in session.list_sinks() {
+                let response = format!("OK:{}:{}:{}:{}:{}\n",
+                    info.id, escape_field(&info.name), escape_field(&info.source), info.player, info.caps.as_str());
+                stream.write_all(response.as_bytes())?;
+            }
+            stream.write_all(b"END_MULTILINE\n")?;
+        }
+        Command::Monitor { target } => {
+            match session.monitor(target, false) {
+                Ok(()) => stream.write_all(b"OK\n")?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::Stop => {
+            match session.stop_monitor() {
+                Ok(()) => stream.write_all(b"OK\n")?,
+                Err(e) => write_err(stream, &e)?,
+            }
+        },
+        Command::Subscribe => {
+            session.subscribe(false);
+            stream.write_all(b"OK\n")?;
+        },
+        Command::Unsubscribe => {
+            session.unsubscribe();
+            stream.write_all(b"OK\n")?;
+        },
+        Command::Shutdown => {
+            session.shutdown();
+            stream.write_all(b"OK\n")?;
+        },
+        Command::Help => stream.write_all(HELP_TEXT)?,
+        // the text parser never produces it, hello only makes sense as JSON
+        Command::Hello { .. } => stream.write_all(b"ERR:Invalid command hello\n")?,
+    }
+
+    Ok(())
+}
+
+pub fn handle_client<S: ControlStream>(stream: S, daemon: Arc<Daemon>) -> Result<()> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut session = Session::new(daemon, writer.clone());
+    let mut buf_reader = BufReader::new(stream);
+
+    loop {
+        let mut buf = Vec::new();
+        if buf_reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(());
+        }
+
+        // JSON requests are always objects, text commands never start with a brace
+        let mut response = Vec::new();
+        match std::str::from_utf8(&buf) {
+            Ok(line) if line.trim().starts_with('{') => {
+                response.extend(json::handle_line(&mut session, line.trim()).into_bytes());
+            },
+            Ok(line) => handle_text(&mut response, &mut session, line.trim())?,
+            // a garbled line is the client's problem, not the connection's
+            Err(e) => {
+                debug!("Rejected non-UTF-8 request: {}", e);
+                let reason = "Request is not valid UTF-8";
+                if String::from_utf8_lossy(&buf).trim_start().starts_with('{') {
+                    response.extend(json::invalid_line(reason).into_bytes());
+                } else {
+                    response.extend(format!("ERR:{}\n", reason).into_bytes());
+                }
+            },
+        }
+
+        writer.lock().unwrap().write_all(&response)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, sink::{Sink, TestSink}};
+    use std::env;
+
+    // A daemon keeping its state file to the test, removed when it's dropped
+    struct TestDaemon(Arc<Daemon>, std::path::PathBuf);
+
+    impl TestDaemon {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("rinputer4-{}-{}.json", name, std::process::id()));
+            TestDaemon(Arc::new(Daemon::new(&Config::for_test(path.clone()))), path)
+        }
+    }
+
+    impl Drop for TestDaemon {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.1);
+        }
+    }
+
+    #[test]
+    fn rebound_sinks_report_the_new_sources_caps() {
+        let daemon = TestDaemon::new("rebind");
+        let (_, source) = OpenedEventSource::fake();
+        let sink = insert_sink(&daemon.0.state, &mut daemon.0.sinks.lock().unwrap(), TestSink::new, source).unwrap();
+        let mut session = Session::new(Arc::clone(&daemon.0), Arc::new(Mutex::new(Vec::new())));
+        assert_eq!(session.list_sinks()[0].caps, SourceCaps::FullX360);
+
+        let (_, mut source) = OpenedEventSource::fake();
+        source.id = "event10".to_string();
+        source.caps = SourceCaps::DpadAndAB;
+        assert_eq!(rebind_sink(&daemon.0, sink, source).unwrap(), sink);
+        let sinks = session.list_sinks();
+        assert_eq!(sinks.len(), 1);
+        assert_eq!(sinks[0].caps, SourceCaps::DpadAndAB);
+
+        let mut text = Vec::new();
+        handle_text(&mut text, &mut session, "list_sinks").unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(), format!("OK:{}:Test:Test pad:1:DpadAndAB\nEND_MULTILINE\n", sink));
+        let json: serde_json::Value = serde_json::to_value(&sinks[0]).unwrap();
+        assert_eq!(json["caps"], "DpadAndAB");
+    }
+
+    #[test]
+    fn no_sinks_get_in_after_teardown() {
+        let daemon = TestDaemon::new("teardown");
+        let (_, source) = OpenedEventSource::fake();
+        insert_sink(&daemon.0.state, &mut daemon.0.sinks.lock().unwrap(), TestSink::new, source).unwrap();
+        daemon.0.teardown();
+
+        let (_, source) = OpenedEventSource::fake();
+        let err = insert_sink(&daemon.0.state, &mut daemon.0.sinks.lock().unwrap(), TestSink::new, source).unwrap_err();
+        assert_eq!(err.code, ErrorCode::SinkCreationFailed);
+        assert_eq!(daemon.0.sinks.lock().unwrap().iter().count(), 0);
+    }
+
+    #[test]
+    fn garbled_lines_get_an_error_and_keep_the_connection() {
+        let daemon = TestDaemon::new("garbled");
+        let (client, server) = UnixStream::pair().unwrap();
+        let daemon2 = Arc::clone(&daemon.0);
+        let thread = std::thread::spawn(move || handle_client(server, daemon2));
+        let mut writer = client.try_clone().unwrap();
+        let mut reader = BufReader::new(client);
+        let mut reply = |req: &[u8]| {
+            writer.write_all(req).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+
+        assert_eq!(reply(b"list_s\xffnks\n"), "ERR:Request is not valid UTF-8\n");
+        let json: serde_json::Value = serde_json::from_str(&reply(b"{\"cmd\": \"\xfe\"}\n")).unwrap();
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["error"]["code"], "invalid_request");
+        assert_eq!(reply(b"list_sinks\n"), "END_MULTILINE\n");
+
+        // hanging up ends the session cleanly
+        reader.into_inner().shutdown(std::net::Shutdown::Both).unwrap();
+        assert!(thread.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn text_fields_keep_their_colons_to_themselves() {
+        assert_eq!(escape_field("8BitDo Pro 2"), "8BitDo Pro 2");
+        assert_eq!(escape_field("usb-0000:00:14.0-1/input0"), "usb-0000%3A00%3A14.0-1/input0");
+        assert_eq!(escape_field("100%\nsure"), "100%25%0Asure");
+    }
+}
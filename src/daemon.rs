@@ -0,0 +1,92 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    pairing::Pairings,
+    persist::State,
+    sink::SinkTable,
+    source::StopFlag,
+};
+
+// How long shutdown waits for pending pairings to release their sources
+static PAIRING_CANCEL_TIMEOUT: Duration = Duration::from_secs(2);
+// The watcher counts as hung if it did not check in for this long
+static WATCHER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Everything shared between client connections and background threads
+pub struct Daemon {
+    pub sinks: Mutex<SinkTable>,
+    pub pairings: Pairings,
+    pub state: State,
+    // set once shutdown started, background threads quit when they see it
+    pub stopping: StopFlag,
+    started: Instant,
+    // ms after `started` the watcher last went through its loop
+    watcher_beat: AtomicU64,
+    shutdown_tx: Mutex<Sender<()>>,
+    shutdown_rx: Mutex<Receiver<()>>,
+}
+
+impl Daemon {
+    pub fn new(config: &Config) -> Self {
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        Self {
+            sinks: Mutex::new(SinkTable::default()),
+            pairings: Pairings::new(config.pairing_timeout),
+            state: State::load(config.state_path.clone()),
+            stopping: StopFlag::default(),
+            started: Instant::now(),
+            watcher_beat: AtomicU64::new(0),
+            shutdown_tx: Mutex::new(shutdown_tx),
+            shutdown_rx: Mutex::new(shutdown_rx),
+        }
+    }
+
+    // Asks main to shut down, safe to call from any thread
+    pub fn request_shutdown(&self) {
+        let _ = self.shutdown_tx.lock().unwrap().send(());
+    }
+
+    pub fn wait_for_shutdown(&self) {
+        let _ = self.shutdown_rx.lock().unwrap().recv();
+    }
+
+    pub fn watcher_alive(&self) {
+        self.watcher_beat.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Checked before every watchdog ping. Locking the sinks also catches a
+    // deadlock, the ping then never happens.
+    pub fn is_healthy(&self) -> bool {
+        let beat = Duration::from_millis(self.watcher_beat.load(Ordering::Relaxed));
+        if self.started.elapsed().saturating_sub(beat) > WATCHER_TIMEOUT {
+            return false;
+        }
+        self.sinks.lock().unwrap()
+            .iter()
+            .all(|(_, sink)| sink.is_healthy())
+    }
+
+    // Cancels pairings and removes every sink. Each sink destroys its
+    // virtual device and ungrabs its source before the next one goes.
+    // The state file is left alone so the sinks come back on next start.
+    pub fn teardown(&self) {
+        // a pairing finishing right now must not slip a sink in after this
+        self.sinks.lock().unwrap().close();
+        self.pairings.cancel_all(PAIRING_CANCEL_TIMEOUT);
+
+        let mut all_sinks = self.sinks.lock().unwrap();
+        for (sink, victim) in all_sinks.take_all() {
+            drop(victim);
+            events::publish(Event::SinkDeleted { sink });
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc,
+    Mutex,
+};
+use serde::Serialize;
+
+use crate::{control::escape_field as esc, sink::SinkId};
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    SourceAdded { path: String, name: String },
+    SourceRemoved { path: String },
+    SinkCreated { sink: SinkId, name: String, source: String },
+    SinkDeleted { sink: SinkId },
+    SinkRebound { sink: SinkId, source: String },
+    SinkSourceLost { sink: SinkId, source: String },
+    // a pad from another machine that can't be let in
+    SenderRejected { source: String, reason: String },
+}
+
+impl Event {
+    // Single line form used by the text protocol
+    pub fn to_text(&self) -> String {
+        match self {
+            Event::SourceAdded { path, name } => format!("EVENT:source_added:{}:{}\n", esc(path), esc(name)),
+            Event::SourceRemoved { path } => format!("EVENT:source_removed:{}\n", esc(path)),
+            Event::SinkCreated { sink, name, source } =>
+                format!("EVENT:sink_created:{}:{}:{}\n", sink, esc(name), esc(source)),
+            Event::SinkDeleted { sink } => format!("EVENT:sink_deleted:{}\n", sink),
+            Event::SinkRebound { sink, source } => format!("EVENT:sink_rebound:{}:{}\n", sink, esc(source)),
+            Event::SinkSourceLost { sink, source } => format!("EVENT:sink_source_lost:{}:{}\n", sink, esc(source)),
+            Event::SenderRejected { source, reason } =>
+                format!("EVENT:sender_rejected:{}:{}\n", esc(source), esc(reason)),
+        }
+    }
+}
+
+static SUBSCRIBERS: Mutex<Vec<(u64, mpsc::Sender<Event>)>> = Mutex::new(Vec::new());
+static NEXT_SUBSCRIBER: AtomicU64 = AtomicU64::new(0);
+
+// Removes the subscriber when dropped, which also ends its receiver
+pub struct Subscription {
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS.lock().unwrap().retain(|(id, _)| *id != self.id);
+    }
+}
+
+pub fn subscribe() -> (Subscription, mpsc::Receiver<Event>) {
+    let (tx, rx) = mpsc::channel();
+    let id = NEXT_SUBSCRIBER.fetch_add(1, Ordering::Relaxed);
+    SUBSCRIBERS.lock().unwrap().push((id, tx));
+    (Subscription { id }, rx)
+}
+
+pub fn publish(event: Event) {
+    SUBSCRIBERS.lock().unwrap().retain(|(_, tx)| tx.send(event.clone()).is_ok());
+}
@@ -0,0 +1,200 @@
+use std::{
+    io::Write,
+    os::unix::net::UnixDatagram,
+};
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+static CRATE: &str = "rinputer4";
+static JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Parsed form of a spec like "info,source=debug,control::json=trace".
+// Module names may leave out the crate name.
+#[derive(Debug, PartialEq)]
+struct Filter {
+    default: LevelFilter,
+    // longest module first so the most specific one wins
+    modules: Vec<(String, LevelFilter)>,
+}
+
+fn parse_level(input: &str) -> Result<LevelFilter> {
+    input.parse::<LevelFilter>()
+        .map_err(|_| anyhow!("Unknown log level {}, expected off, error, warn, info, debug or trace", input))
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Self> {
+        let mut ret = Filter { default: LevelFilter::Info, modules: Vec::new() };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = if module == CRATE || module.starts_with("rinputer4::") {
+                        module.to_string()
+                    } else {
+                        format!("{}::{}", CRATE, module)
+                    };
+                    ret.modules.push((module, parse_level(level)?));
+                },
+                None => ret.default = parse_level(directive)?,
+            }
+        }
+        ret.modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(ret)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .find(|(module, _)| target == module
+                || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules.iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// One field of the journal native protocol, values with newlines have to
+// be sent length-prefixed
+fn journal_field(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        out.push(b'\n');
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        out.push(b'=');
+    }
+    out.extend_from_slice(value.as_bytes());
+    out.push(b'\n');
+}
+
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let mut out = Vec::new();
+    journal_field(&mut out, "PRIORITY", &priority(record.level()).to_string());
+    journal_field(&mut out, "SYSLOG_IDENTIFIER", CRATE);
+    journal_field(&mut out, "MESSAGE", &record.args().to_string());
+    journal_field(&mut out, "CODE_MODULE", record.target());
+    if let Some(file) = record.file() {
+        journal_field(&mut out, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        journal_field(&mut out, "CODE_LINE", &line.to_string());
+    }
+    out
+}
+
+struct Logger {
+    filter: Filter,
+    journal: Option<UnixDatagram>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(journal) = self.journal.as_ref() {
+            if journal.send_to(&journal_entry(record), JOURNAL_SOCKET).is_ok() {
+                return;
+            }
+        }
+
+        let target = record.target().strip_prefix("rinputer4::").unwrap_or(record.target());
+        let _ = writeln!(std::io::stderr(), "{:<5} {}: {}", record.level(), target, record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+// `spec` is a filter like "info,source=debug". With `journal` messages are
+// sent to journald with their priority and module as fields, stderr is only
+// used if that fails.
+pub fn init(spec: &str, journal: bool) -> Result<()> {
+    let filter = Filter::parse(spec)?;
+    let journal = if journal {
+        Some(UnixDatagram::unbound()?)
+    } else {
+        None
+    };
+
+    log::set_max_level(filter.max());
+    log::set_logger(Box::leak(Box::new(Logger { filter, journal })))
+        .map_err(|e| anyhow!("{}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_default_and_modules() {
+        let filter = Filter::parse("warn, source=debug,rinputer4::control::json=trace").unwrap();
+        assert_eq!(filter.default, LevelFilter::Warn);
+        assert_eq!(filter.level_for("rinputer4::source::event"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("rinputer4::source"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("rinputer4::control::json"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("rinputer4::control"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("rinputer4::sourcex"), LevelFilter::Warn);
+        assert_eq!(filter.max(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn most_specific_module_wins() {
+        let filter = Filter::parse("source::event=off,source=trace").unwrap();
+        assert_eq!(filter.level_for("rinputer4::source::event"), LevelFilter::Off);
+        assert_eq!(filter.level_for("rinputer4::source::quirks_db"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("rinputer4::sink"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert!(Filter::parse("loud").is_err());
+        assert!(Filter::parse("source=loud").is_err());
+        assert_eq!(Filter::parse("").unwrap().default, LevelFilter::Info);
+    }
+
+    #[test]
+    fn encodes_journal_fields() {
+        let mut out = Vec::new();
+        journal_field(&mut out, "MESSAGE", "hello");
+        assert_eq!(out, b"MESSAGE=hello\n");
+
+        let mut out = Vec::new();
+        journal_field(&mut out, "MESSAGE", "a\nb");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn journal_entry_has_priority_and_module() {
+        let args = format_args!("Grabbed event3");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Warn)
+            .target("rinputer4::source::event")
+            .build();
+        let entry = String::from_utf8(journal_entry(&record)).unwrap();
+        assert!(entry.contains("PRIORITY=4\n"));
+        assert!(entry.contains("MESSAGE=Grabbed event3\n"));
+        assert!(entry.contains("CODE_MODULE=rinputer4::source::event\n"));
+        assert!(entry.contains("SYSLOG_IDENTIFIER=rinputer4\n"));
+    }
+}
@@ -1,120 +1,126 @@
 mod source;
 mod sink;
-
-use std::net::{
-    TcpListener,
-    TcpStream,
-};
-use std::io::{
-    self,
-    BufReader,
-    BufRead,
-    Write,
-};
-use std::sync::{
-    Arc,
-    Mutex,
-};
-use anyhow::Result;
+mod control;
+mod config;
+mod events;
+mod watcher;
+mod daemon;
+mod pairing;
+mod persist;
+mod monitor;
+mod systemd;
+mod logging;
+mod receive;
+
+use std::fs;
+use std::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+use nix::sys::signal::{SigSet, Signal};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
 
 use crate::{
-    sink::Sink,
+    config::Config,
+    daemon::Daemon,
+    control::listener::{self, AccessPolicy},
+    sink::net,
     source::OpenedEventSource,
+    systemd::Listener,
 };
 
-static HELP_TEXT: &[u8] = b"Available commands are:
-list_sinks: Lists all sinks in use with sources attached to them
-add_sink: Adds a sink and autobinds a source
-del_sink: Removes a sink
-list_sink_types: Lists sink types that can be added with add_sink
-help: Displays this message
-";
-
-fn handle_client(mut stream: TcpStream, all_sinks_mutex: Arc<Mutex<Vec<Box<dyn Sink>>>>) -> Result<()> {
-    let sink_types = sink::list_names();
-
-    loop {
-        let mut buf = String::new();
-        let mut buf_reader = BufReader::new(&mut stream);
-        buf_reader.read_line(&mut buf)?;
-
-        let args: Vec<&str> = buf.trim().split(' ').collect();
-
-        match args[0] {
-            "add_sink" => {
-                let snk_type = args[1].parse::<usize>()?;
-                let mut all_sinks = all_sinks_mutex.lock().unwrap();
-                let (_, new_fn) = sink_types[snk_type];
-
-                let cur_sources = source::enumerate().into_iter()
-                    .map(|v| source::into_opened(v))
-                    .collect::<Vec<OpenedEventSource>>();
-                let new_source = source::wait_for_lr(cur_sources);
-                match new_fn(new_source) {
-                    Ok(sink) => {
-                        all_sinks.push(sink);
-                        stream.write_all(b"OK\n")?;
-                    }
-                    Err(e) => {
-                        eprintln!("Failed making a new sink:");
-                        eprintln!("{}", e);
-                        stream.write_all(b"ERR\n")?;
-                    }
-                };
-            },
-            "del_sink" => {
-                let mut all_sinks = all_sinks_mutex.lock().unwrap();
-                let victim = args[1].parse::<usize>()?;
-                all_sinks.remove(victim);
-                stream.write_all(b"OK\n")?;
-            }
-            "list_sink_types" => {
-                for (i, (name, _)) in sink_types.iter().enumerate() {
-                    let tmp = format!("OK:{}:{}", i, name);
-                    stream.write_all(tmp.as_str().as_bytes())?;
-                    stream.write_all(b"\n")?;
-                }
-                stream.write_all(b"END_MULTILINE\n")?;
-            },
-            "list_sinks" => {
-                let all_sinks = all_sinks_mutex.lock().unwrap();
-                for (i, sink) in all_sinks.iter().enumerate() {
-                    let response = format!("OK:{}:{}:{}\n", i, sink.name(), sink.source_name());
-                    stream.write_all(response.as_str().as_bytes())?;
-                }
-                stream.write_all(b"END_MULTILINE\n")?;
-            }
-            "help" => stream.write_all(HELP_TEXT)?,
-            _ => stream.write_all(b"ERR:Invalid command\n")?,
+fn main() -> Result<()> {
+    // touches the environment, nothing may run alongside yet
+    let listen_fds = systemd::take_listen_fds();
+    let config = Config::from_args()?;
+    logging::init(&config.log_filter, config.log_journal)?;
+
+    // blocked before any thread exists so all of them inherit the mask and
+    // only the signal thread below ever sees these
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+
+    let daemon = Arc::new(Daemon::new(&config));
+    let ptr = Arc::clone(&daemon);
+    std::thread::spawn(move || {
+        if signals.wait().is_ok() {
+            ptr.request_shutdown();
+        }
+    });
+
+    daemon.state.try_restore(&mut daemon.sinks.lock().unwrap());
+    let ptr = Arc::clone(&daemon);
+    let watcher = std::thread::spawn(move || watcher::watch(ptr));
+
+    // with socket activation systemd owns the sockets, don't bind or remove ours
+    let mut unix = None;
+    let mut tcp = Vec::new();
+    for activated in systemd::listeners(listen_fds) {
+        match activated {
+            Listener::Unix(l) if unix.is_none() => unix = Some(l),
+            Listener::Unix(_) => warn!("Ignoring extra activated unix socket"),
+            Listener::Tcp(l) => tcp.push(l),
         }
-
-        println!("{:#?}", args);
     }
-}
+    let activated = unix.is_some();
+    // joined at shutdown, so no new client or sender is let in past teardown
+    let mut listeners = Vec::new();
 
-fn main() -> io::Result<()> {
+    if let Some(addr) = config.tcp_addr.as_ref() {
+        tcp.push(TcpListener::bind(addr)?);
+    }
+    for l in tcp {
+        info!("Listening on {}", l.local_addr()?);
+        let ptr = Arc::clone(&daemon);
+        listeners.push(std::thread::spawn(move || listener::serve_tcp(l, ptr)));
+    }
 
+    if let Some(addr) = config.receive_addr.as_ref() {
+        let new_sink = sink::find_by_name(&config.receive_as)
+            .ok_or_else(|| anyhow!("Unknown sink type {}", config.receive_as))?;
+        let psk = config.psk_file.as_deref().map(net::read_psk).transpose()?;
+        let opts = Arc::new(receive::Options { new_sink, psk });
+        let udp = UdpSocket::bind(addr)?;
+        let tcp = TcpListener::bind(addr)?;
+        info!("Receiving pads on {} over UDP and {} over TCP", udp.local_addr()?, tcp.local_addr()?);
+        let (ptr, opts2) = (Arc::clone(&daemon), Arc::clone(&opts));
+        listeners.push(std::thread::spawn(move || receive::serve_udp(udp, ptr, opts2)));
+        let ptr = Arc::clone(&daemon);
+        listeners.push(std::thread::spawn(move || receive::serve_tcp(tcp, ptr, opts)));
+    }
 
-    let mutex = Arc::new(Mutex::new(Vec::new()));
-    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-    println!("Listening on {}", listener.local_addr().unwrap());
-    for stream in listener.incoming() {
-        let ptr = Arc::clone(&mutex);
-        std::thread::spawn(move || handle_client(stream.unwrap(), ptr));
+    let unix = match unix {
+        Some(l) => l,
+        None => listener::bind_unix(&config.socket_path)?,
     };
-
-    Ok(())
-    /*
-    let devices = source::enumerate();
-    for (i, device) in devices.iter().enumerate() {
-        println!("Device {}: {}", i, device.name())
+    if activated {
+        info!("Listening on socket passed by systemd");
+    } else {
+        info!("Listening on {}", config.socket_path.display());
+    }
+    let policy = AccessPolicy::new(config.allowed_uids, config.allowed_gids);
+    let ptr = Arc::clone(&daemon);
+    listeners.push(std::thread::spawn(move || listener::serve_unix(unix, policy, ptr)));
+
+    let found = source::enumerate().len();
+    info!("Found {} input devices", found);
+    systemd::notify(&format!("READY=1\nSTATUS=Found {} input devices", found));
+    let ptr = Arc::clone(&daemon);
+    std::thread::spawn(move || systemd::watchdog(ptr));
+
+    daemon.wait_for_shutdown();
+    info!("Shutting down");
+    systemd::notify("STOPPING=1");
+    daemon.stopping.stop();
+    let _ = watcher.join();
+    for l in listeners {
+        let _ = l.join();
+    }
+    daemon.teardown();
+    if !activated {
+        let _ = fs::remove_file(&config.socket_path);
     }
-    println!("Which device should be used for 1st gamepad?");
-
-    let mut buf = String::new();
-    io::stdin().read_line(&mut buf).unwrap();
-
-    let dev_idx = buf.trim().parse::<usize>().unwrap();
 
-    println!("Using device {}", devices[dev_idx].name());*/
+    Ok(())
 }
@@ -0,0 +1,195 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, TrySendError},
+        Arc,
+        Mutex,
+    },
+    time::SystemTime,
+};
+use evdev::{InputEvent, InputEventKind};
+use serde::Serialize;
+use log::warn;
+
+use crate::source::StopFlag;
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    // as read from the device, before quirks
+    Raw,
+    // what a sink gets after remaps and the Joy-Con middleman
+    Output,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename = "monitor")]
+pub struct MonitorEvent {
+    pub target: String,
+    pub stage: Stage,
+    // seconds since the epoch, kernel timestamp if the event has one
+    pub time: f64,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub code: String,
+    pub value: i32,
+}
+
+impl MonitorEvent {
+    fn new(stage: Stage, target: &str, ev: &InputEvent) -> Self {
+        let code = match ev.kind() {
+            InputEventKind::Synchronization(v) => format!("{:?}", v),
+            InputEventKind::Key(v) => format!("{:?}", v),
+            InputEventKind::RelAxis(v) => format!("{:?}", v),
+            InputEventKind::AbsAxis(v) => format!("{:?}", v),
+            InputEventKind::Misc(v) => format!("{:?}", v),
+            InputEventKind::Switch(v) => format!("{:?}", v),
+            InputEventKind::Led(v) => format!("{:?}", v),
+            InputEventKind::Sound(v) => format!("{:?}", v),
+            _ => ev.code().to_string(),
+        };
+        let time = match ev.timestamp() {
+            SystemTime::UNIX_EPOCH => SystemTime::now(),
+            stamp => stamp,
+        };
+
+        Self {
+            target: target.to_string(),
+            stage,
+            time: time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            event_type: format!("{:?}", ev.event_type()),
+            code,
+            value: ev.value(),
+        }
+    }
+
+    // Single line form used by the text protocol
+    pub fn to_text(&self) -> String {
+        let stage = match self.stage {
+            Stage::Raw => "raw",
+            Stage::Output => "output",
+        };
+        format!("MONITOR:{}:{}:{:.6}:{}:{}:{}\n",
+            self.target, stage, self.time, self.event_type, self.code, self.value)
+    }
+}
+
+struct Tap {
+    id: u64,
+    stage: Stage,
+    // source ID for raw taps, source ID of the sink for output taps
+    key: String,
+    tx: mpsc::SyncSender<MonitorEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+// Events a monitor may have queued, more are dropped instead of piling up
+// while its client doesn't read
+const QUEUE_LEN: usize = 4096;
+
+static TAPS: Mutex<Vec<Tap>> = Mutex::new(Vec::new());
+// lets the hot path skip the lock while nobody is monitoring
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_TAP: AtomicU64 = AtomicU64::new(0);
+
+// Removes all taps of one monitor when dropped, which also ends its receiver
+pub struct Monitor {
+    ids: Vec<u64>,
+    tx: mpsc::SyncSender<MonitorEvent>,
+    // events that didn't fit into the queue
+    dropped: Arc<AtomicU64>,
+    // for threads reading a source only on behalf of this monitor
+    stop: StopFlag,
+}
+
+impl Monitor {
+    pub fn new() -> (Self, mpsc::Receiver<MonitorEvent>) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        (Self { ids: Vec::new(), tx, dropped: Arc::default(), stop: StopFlag::default() }, rx)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn stop_flag(&self) -> StopFlag {
+        self.stop.clone()
+    }
+
+    pub fn tap(&mut self, stage: Stage, key: &str) {
+        let id = NEXT_TAP.fetch_add(1, Ordering::Relaxed);
+        let mut taps = TAPS.lock().unwrap();
+        taps.push(Tap { id, stage, key: key.to_string(), tx: self.tx.clone(), dropped: Arc::clone(&self.dropped) });
+        ACTIVE.store(taps.len(), Ordering::Relaxed);
+        self.ids.push(id);
+    }
+
+    // Removes every tap, events keep coming from the ones added after
+    pub fn untap(&mut self) {
+        let mut taps = TAPS.lock().unwrap();
+        taps.retain(|tap| !self.ids.contains(&tap.id));
+        ACTIVE.store(taps.len(), Ordering::Relaxed);
+        self.ids.clear();
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if self.dropped() > 0 {
+            warn!("A monitor client fell behind, {} events were dropped", self.dropped());
+        }
+        self.untap();
+    }
+}
+
+pub fn publish(stage: Stage, key: &str, ev: &InputEvent) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let taps = TAPS.lock().unwrap();
+    for tap in taps.iter().filter(|t| t.stage == stage && t.key == key) {
+        // a gone receiver is cleaned up when its Monitor is dropped
+        if let Err(TrySendError::Full(_)) = tap.tx.try_send(MonitorEvent::new(stage, key, ev)) {
+            tap.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{EventType, Key};
+
+    #[test]
+    fn slow_clients_lose_events_instead_of_piling_them_up() {
+        let (mut monitor, rx) = Monitor::new();
+        monitor.tap(Stage::Output, "monitor-test");
+        let ev = InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1);
+        for _ in 0..QUEUE_LEN + 10 {
+            publish(Stage::Output, "monitor-test", &ev);
+        }
+        assert_eq!(monitor.dropped(), 10);
+        assert_eq!(rx.try_iter().count(), QUEUE_LEN);
+
+        // reading makes room again
+        publish(Stage::Output, "monitor-test", &ev);
+        assert_eq!(rx.try_iter().count(), 1);
+        assert_eq!(monitor.dropped(), 10);
+    }
+
+    #[test]
+    fn taps_can_be_moved() {
+        let (mut monitor, rx) = Monitor::new();
+        monitor.tap(Stage::Output, "monitor-old");
+        let ev = InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1);
+        publish(Stage::Output, "monitor-old", &ev);
+
+        monitor.untap();
+        monitor.tap(Stage::Output, "monitor-new");
+        publish(Stage::Output, "monitor-old", &ev);
+        publish(Stage::Output, "monitor-new", &ev);
+        let targets: Vec<String> = rx.try_iter().map(|e| e.target).collect();
+        assert_eq!(targets, ["monitor-old", "monitor-new"]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::source::{self, OpenedEventSource, POLL_INTERVAL};
+
+pub type PairingId = u64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PairingError {
+    Cancelled,
+    TimedOut,
+}
+
+type PendingMap = Arc<Mutex<HashMap<PairingId, Arc<AtomicBool>>>>;
+// IDs of sources held by some pending pairing
+type ClaimSet = Arc<Mutex<HashSet<String>>>;
+
+// Keeps track of pairings that are still waiting for their source
+pub struct Pairings {
+    timeout: Duration,
+    next_id: AtomicU64,
+    pending: PendingMap,
+    claimed: ClaimSet,
+}
+
+// One pending pairing, it is unregistered and gives up its claims once dropped
+pub struct PairingTicket {
+    id: PairingId,
+    cancelled: Arc<AtomicBool>,
+    deadline: Instant,
+    pending: PendingMap,
+    claimed: ClaimSet,
+    claims: Mutex<Vec<String>>,
+}
+
+impl Pairings {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: AtomicU64::new(0),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            claimed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn start(&self) -> PairingTicket {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.pending.lock().unwrap().insert(id, Arc::clone(&cancelled));
+
+        PairingTicket {
+            id,
+            cancelled,
+            deadline: Instant::now() + self.timeout,
+            pending: Arc::clone(&self.pending),
+            claimed: Arc::clone(&self.claimed),
+            claims: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Cancels every pending pairing and waits until they let go of their
+    // sources, gives up after `timeout`
+    pub fn cancel_all(&self, timeout: Duration) {
+        for flag in self.pending.lock().unwrap().values() {
+            flag.store(true, Ordering::Relaxed);
+        }
+
+        let deadline = Instant::now() + timeout;
+        while !self.pending.lock().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    // Returns false if there is no such pairing, or it already finished
+    pub fn cancel(&self, id: PairingId) -> bool {
+        match self.pending.lock().unwrap().get(&id) {
+            Some(flag) => {
+                flag.store(true, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl PairingTicket {
+    pub fn id(&self) -> PairingId {
+        self.id
+    }
+
+    pub fn should_stop(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || Instant::now() >= self.deadline
+    }
+
+    fn error(&self) -> PairingError {
+        if self.cancelled.load(Ordering::Relaxed) {
+            PairingError::Cancelled
+        } else {
+            PairingError::TimedOut
+        }
+    }
+
+    // Opens every source that is not bound (per `is_bound`) and not claimed
+    // by another pairing, and claims it for this one. Claims last as long as
+    // the ticket, so a Joy-Con half found by one scan is still held when the
+    // other half turns up in a later one.
+    fn claim_free(&self, is_bound: &impl Fn(&str) -> bool) -> Vec<OpenedEventSource> {
+        let mut ret = Vec::new();
+        for input in source::enumerate() {
+            let id = input.id();
+            if is_bound(&id) || !self.claimed.lock().unwrap().insert(id.clone()) {
+                continue;
+            }
+            // grabbed by something outside the daemon
+            match source::into_opened(input) {
+                Ok(dev) => {
+                    self.claims.lock().unwrap().push(id);
+                    ret.push(dev);
+                },
+                Err(_) => {
+                    self.claimed.lock().unwrap().remove(&id);
+                },
+            }
+        }
+        ret
+    }
+
+    // Waits for L+R on any free source, including ones that become free
+    // while waiting
+    pub fn wait_for_source(&self, is_bound: impl Fn(&str) -> bool) -> Result<OpenedEventSource, PairingError> {
+        source::wait_for_lr(|| self.claim_free(&is_bound), || self.should_stop())
+            .ok_or_else(|| self.error())
+    }
+}
+
+impl Drop for PairingTicket {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+        let mut claimed = self.claimed.lock().unwrap();
+        for id in self.claims.lock().unwrap().iter() {
+            claimed.remove(id);
+        }
+    }
+}
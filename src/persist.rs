@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use log::{error, warn};
+
+use crate::{
+    events::{self, Event},
+    sink::{self, SinkTable},
+    source::{self, EventSource, OpenedEventSource, SourceIdentity},
+};
+
+const STATE_VERSION: u32 = 1;
+
+// A sink as it is written to the state file. Sources are saved by identity
+// instead of their event node, node numbers change across reboots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedSink {
+    pub sink_type: String,
+    pub devices: Vec<SourceIdentity>,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StateFile {
+    version: u32,
+    sinks: Vec<SavedSink>,
+}
+
+pub struct State {
+    path: PathBuf,
+    // saved sinks whose sources did not show up yet
+    pending: Mutex<Vec<SavedSink>>,
+}
+
+fn read_state(path: &PathBuf) -> Result<Vec<SavedSink>> {
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let state: StateFile = serde_json::from_str(&data)?;
+    if state.version != STATE_VERSION {
+        bail!("Unsupported state file version {}", state.version);
+    }
+    Ok(state.sinks)
+}
+
+fn saved_from(sink: &dyn sink::Sink) -> SavedSink {
+    SavedSink {
+        sink_type: sink.name().to_string(),
+        devices: sink.source_devices(),
+        settings: sink.settings(),
+    }
+}
+
+// Picks one not yet used source per saved device, None if any is missing
+fn find_sources(devices: &[SourceIdentity], available: &mut Vec<Box<dyn EventSource>>)
+    -> Option<Vec<Box<dyn EventSource>>>
+{
+    let mut picked = Vec::new();
+    for wanted in devices {
+        match available.iter().position(|s| wanted.matches(&s.identity())) {
+            Some(idx) => picked.push(available.swap_remove(idx)),
+            None => {
+                available.append(&mut picked);
+                return None;
+            },
+        }
+    }
+    Some(picked)
+}
+
+fn open_saved(mut picked: Vec<Box<dyn EventSource>>) -> Result<OpenedEventSource> {
+    match picked.len() {
+        1 => source::open_single(picked.remove(0)),
+        2 => {
+            // Joy-Con pairs are the only multi-device sources right now
+            if picked[1].name().contains("Left") {
+                picked.swap(0, 1);
+            }
+            let right = source::into_opened(picked.remove(1))?;
+            let left = source::into_opened(picked.remove(0))?;
+            Ok(source::combine_joycons(left, right))
+        },
+        n => bail!("Don't know how to combine {} devices", n),
+    }
+}
+
+impl State {
+    pub fn load(path: PathBuf) -> Self {
+        let pending = match read_state(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Ignoring state file {}: {}", path.display(), e);
+                Vec::new()
+            },
+        };
+        Self { path, pending: Mutex::new(pending) }
+    }
+
+    // Remembers a sink whose source went away, so it comes back with it
+    pub fn requeue(&self, sink: &dyn sink::Sink) {
+        if !sink.source_devices().is_empty() {
+            self.pending.lock().unwrap().push(saved_from(sink));
+        }
+    }
+
+    // Writes the current sinks, plus restores still waiting for their sources
+    pub fn save(&self, sinks: &SinkTable) {
+        // sinks without devices, like pads from other machines, can't be found again
+        let mut saved: Vec<SavedSink> = sinks.iter()
+            .filter(|(_, sink)| !sink.source_devices().is_empty())
+            .map(|(_, sink)| saved_from(sink))
+            .collect();
+        saved.extend(self.pending.lock().unwrap().iter().cloned());
+
+        if let Err(e) = self.write(saved) {
+            error!("Failed to save state to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn write(&self, sinks: Vec<SavedSink>) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_string_pretty(&StateFile { version: STATE_VERSION, sinks })?;
+
+        // write and rename so a crash never leaves a half written file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    // Recreates every pending sink whose sources are all present
+    pub fn try_restore(&self, sinks: &mut SinkTable) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return;
+        }
+
+        let mut available = source::enumerate();
+        let mut restored = false;
+        pending.retain(|saved| {
+            let new_fn = match sink::find_by_name(&saved.sink_type) {
+                Some(f) => f,
+                None => {
+                    warn!("Dropping saved sink of unknown type {}", saved.sink_type);
+                    return false;
+                },
+            };
+            let picked = match find_sources(&saved.devices, &mut available) {
+                Some(p) => p,
+                None => return true,
+            };
+            // most likely grabbed by someone else, retry on next hotplug
+            let src = match open_saved(picked) {
+                Ok(s) => s,
+                Err(_) => return true,
+            };
+            let source_name = src.name.clone();
+            let mut new_sink = match new_fn(src) {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Failed to restore {}: {}", saved.sink_type, e);
+                    return true;
+                },
+            };
+            for (key, value) in saved.settings.iter() {
+                if let Err(e) = new_sink.set_setting(key, value) {
+                    warn!("Ignoring saved setting {}: {}", key, e);
+                }
+            }
+
+            let name = new_sink.name().to_string();
+            let sink = match sinks.insert(new_sink) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Not restoring {}: {}", saved.sink_type, e);
+                    return true;
+                },
+            };
+            events::publish(Event::SinkCreated { sink, name, source: source_name });
+            restored = true;
+            false
+        });
+        drop(pending);
+
+        if restored {
+            self.save(sinks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Sink, TestSink};
+    use std::env;
+
+    fn sink_with(devices: Vec<SourceIdentity>) -> Box<dyn Sink> {
+        let (_, mut source) = OpenedEventSource::fake();
+        source.devices = devices;
+        TestSink::new(source).unwrap()
+    }
+
+    #[test]
+    fn lost_sinks_wait_for_their_source_again() {
+        let path = env::temp_dir().join(format!("rinputer4-state-{}.json", std::process::id()));
+        let state = State::load(path.clone());
+        let pad = SourceIdentity {
+            name: "Pad".to_string(),
+            phys: "usb-1/input0".to_string(),
+            uniq: String::new(),
+            vendor: 0x045e,
+            product: 0x028e,
+        };
+
+        let mut sink = sink_with(vec![pad.clone()]);
+        sink.set_setting("speed", "fast").unwrap();
+        state.requeue(sink.as_ref());
+        // nothing to find a remote pad by
+        state.requeue(sink_with(Vec::new()).as_ref());
+
+        state.save(&SinkTable::default());
+        let saved = read_state(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].sink_type, "Test");
+        assert_eq!(saved[0].devices, [pad]);
+        assert_eq!(saved[0].settings["speed"], "fast");
+    }
+}
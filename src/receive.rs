@@ -0,0 +1,573 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use evdev::{EventType, InputEvent, Synchronization};
+use log::{error, info, warn};
+
+use crate::{
+    control::listener::wait_for_client,
+    daemon::Daemon,
+    events::{self, Event},
+    sink::{
+        net::{self, Header, Packet, Sequence, Verdict},
+        NewSinkFn,
+        SinkId,
+    },
+    source::{event_channel, EventSender, OpenedEventSource, StopFlag, POLL_INTERVAL},
+};
+
+// A sender that stays quiet this long is gone, it sends full state every
+// net::STATE_INTERVAL even while nothing happens
+static LINK_TIMEOUT: Duration = Duration::from_secs(3);
+// A sender that keeps getting rejected is reported again this often
+static REPORT_INTERVAL: Duration = Duration::from_secs(30);
+// UDP senders tracked at once, spoofed addresses must not grow this forever
+const MAX_REMOTES: usize = 32;
+
+// How pads from other machines become local ones
+pub struct Options {
+    pub new_sink: NewSinkFn,
+    pub psk: Option<Vec<u8>>,
+}
+
+// Tells a rejected sender apart from a pad that just doesn't show up,
+// both in the log and to control clients
+fn reject(source: &str, reason: &str) {
+    warn!("Rejecting {}: {}", source, reason);
+    events::publish(Event::SenderRejected { source: source.to_string(), reason: reason.to_string() });
+}
+
+// Keeps a sender that is rejected over and over from flooding everyone
+#[derive(Default)]
+struct Throttle(Option<Instant>);
+
+impl Throttle {
+    fn expired(&self) -> bool {
+        self.0.is_none_or(|last| last.elapsed() >= REPORT_INTERVAL)
+    }
+
+    fn due(&mut self) -> bool {
+        let ret = self.expired();
+        if ret {
+            self.0 = Some(Instant::now());
+        }
+        ret
+    }
+}
+
+// Turns the packets of one sender back into a source
+pub struct Feed {
+    seq: Sequence,
+    tx: Option<EventSender>,
+    // what a state has to carry to start a session, a new one once it did
+    nonce: u64,
+    // the last session that was sent the nonce, and how many states it
+    // sent without it since
+    challenged: Option<(u32, u32)>,
+    reported: Throttle,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self { seq: Sequence::default(), tx: None, nonce: net::new_nonce(), challenged: None, reported: Throttle::default() }
+    }
+}
+
+impl Feed {
+    // What to send along with a resync
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+
+    // Returns a source once the first full state of a session arrived,
+    // which a new pad should be made for, and whether the sender should
+    // send full state
+    pub fn take(&mut self, id: &str, header: Header, packet: Packet) -> (Option<OpenedEventSource>, bool) {
+        if let Packet::Resync { .. } = packet {
+            return (None, false);
+        }
+        let old_session = self.seq.session();
+        if let Packet::State { nonce, .. } = packet {
+            if old_session != Some(header.session) && nonce != self.nonce {
+                let strikes = match self.challenged {
+                    Some((session, n)) if session == header.session => n + 1,
+                    _ => 0,
+                };
+                self.challenged = Some((header.session, strikes));
+                // one state might have been on its way when the nonce went
+                // out, a sender that keeps going without it is a recording
+                if strikes >= 2 && self.reported.due() {
+                    reject(id, "it keeps sending states without the current nonce, likely a replay");
+                }
+                return (None, true);
+            }
+        }
+        let verdict = self.seq.check(header, matches!(packet, Packet::State { .. }));
+        let resync = matches!(verdict, Verdict::Resync | Verdict::ApplyAndResync);
+        if matches!(verdict, Verdict::Resync | Verdict::Drop) {
+            return (None, resync);
+        }
+
+        let mut source = None;
+        let events = match packet {
+            Packet::Frame(events) => events,
+            Packet::State { name, caps, axes, events, .. } => {
+                // a restarted sender gets a new pad, the old one lets go of
+                // everything once its channel closes
+                if old_session != Some(header.session) {
+                    // the state that started it can't start another one
+                    self.nonce = net::new_nonce();
+                    self.challenged = None;
+                    let (tx, chan) = event_channel();
+                    self.tx = Some(tx);
+                    source = Some(OpenedEventSource {
+                        id: id.to_string(),
+                        name,
+                        path: id.to_string(),
+                        caps,
+                        axes,
+                        rumble: Vec::new(),
+                        motion: Vec::new(),
+                        devices: Vec::new(),
+                        chan,
+                        stop: StopFlag::default(),
+                        threads: Vec::new(),
+                    });
+                }
+                events
+            },
+            Packet::Resync { .. } => unreachable!("handled above"),
+        };
+
+        if let Some(tx) = &self.tx {
+            let syn = InputEvent::new_now(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0);
+            for ev in events.into_iter().chain([syn]) {
+                // the pad might be gone already
+                let _ = tx.send(ev);
+            }
+        }
+        (source, resync)
+    }
+}
+
+// A sender's pad on this machine
+struct Remote {
+    feed: Feed,
+    sink: Option<SinkId>,
+    last_heard: Instant,
+}
+
+impl Remote {
+    fn new() -> Self {
+        Self { feed: Feed::default(), sink: None, last_heard: Instant::now() }
+    }
+
+    // Returns whether the sender should send full state
+    fn take(&mut self, daemon: &Daemon, opts: &Options, id: &str, header: Header, packet: Packet) -> bool {
+        self.last_heard = Instant::now();
+        let (source, resync) = self.feed.take(id, header, packet);
+        if let Some(source) = source {
+            self.detach(daemon);
+            self.attach(daemon, opts, source);
+        }
+        resync
+    }
+
+    fn attach(&mut self, daemon: &Daemon, opts: &Options, source: OpenedEventSource) {
+        let (id, source_name) = (source.id.clone(), source.name.clone());
+        let new_sink = match (opts.new_sink)(source) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to create a pad for {}: {}", id, e);
+                // try again with the next full state
+                self.feed = Feed::default();
+                return;
+            },
+        };
+        let name = new_sink.name().to_string();
+        let sink = match daemon.sinks.lock().unwrap().insert(new_sink) {
+            Ok(s) => s,
+            Err(e) => {
+                info!("No pad for {}: {}", id, e);
+                return;
+            },
+        };
+        events::publish(Event::SinkCreated { sink, name, source: source_name });
+        self.sink = Some(sink);
+    }
+
+    fn detach(&mut self, daemon: &Daemon) {
+        if let Some(sink) = self.sink.take() {
+            // shutdown might have removed it already
+            let removed = daemon.sinks.lock().unwrap().remove(sink);
+            if removed.is_some() {
+                drop(removed);
+                events::publish(Event::SinkDeleted { sink });
+            }
+        }
+    }
+}
+
+// Returns whether another sender fits in. Only a sender that got past the
+// nonce has a pad, the oldest one still without it is dropped to make room.
+fn make_room(remotes: &mut HashMap<SocketAddr, Remote>) -> bool {
+    if remotes.len() < MAX_REMOTES {
+        return true;
+    }
+    let oldest = remotes.iter()
+        .filter(|(_, remote)| remote.sink.is_none())
+        .min_by_key(|(_, remote)| remote.last_heard)
+        .map(|(peer, _)| *peer);
+    match oldest {
+        Some(peer) => {
+            remotes.remove(&peer);
+            true
+        },
+        None => false,
+    }
+}
+
+fn source_id(peer: &SocketAddr) -> String {
+    format!("net:{}", peer)
+}
+
+pub fn serve_udp(socket: UdpSocket, daemon: Arc<Daemon>, opts: Arc<Options>) {
+    if let Err(e) = socket.set_read_timeout(Some(POLL_INTERVAL)) {
+        error!("Can't receive pads: {}", e);
+        return;
+    }
+    let mut remotes: HashMap<SocketAddr, Remote> = HashMap::new();
+    // peers whose packets were rejected lately
+    let mut rejected: HashMap<SocketAddr, Throttle> = HashMap::new();
+    let mut buf = vec![0u8; u16::MAX as usize];
+
+    while !daemon.stopping.is_stopped() {
+        if let Ok((n, peer)) = socket.recv_from(&mut buf) {
+            match net::decode(&buf[..n], opts.psk.as_deref()) {
+                Ok((header, packet)) => {
+                    if remotes.contains_key(&peer) || make_room(&mut remotes) {
+                        let remote = remotes.entry(peer).or_insert_with(Remote::new);
+                        if remote.take(&daemon, &opts, &source_id(&peer), header, packet) {
+                            let resync = Packet::Resync { nonce: remote.feed.nonce() };
+                            let _ = socket.send_to(&net::encode(header, &resync, opts.psk.as_deref()), peer);
+                        }
+                    } else if rejected.entry(peer).or_default().due() {
+                        reject(&source_id(&peer), "too many senders already");
+                    }
+                },
+                Err(e) => {
+                    if rejected.entry(peer).or_default().due() {
+                        reject(&source_id(&peer), &e.to_string());
+                    }
+                },
+            }
+        }
+        rejected.retain(|_, throttle| !throttle.expired());
+
+        remotes.retain(|peer, remote| {
+            if remote.last_heard.elapsed() < LINK_TIMEOUT {
+                return true;
+            }
+            info!("{} stopped sending", peer);
+            remote.detach(&daemon);
+            false
+        });
+    }
+}
+
+// Reads one length prefixed packet off a TCP stream
+pub fn read_packet(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut ret = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut ret)?;
+    Ok(ret)
+}
+
+// Writes one length prefixed packet to a TCP stream
+pub fn write_packet(stream: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let mut buf = (data.len() as u16).to_be_bytes().to_vec();
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)
+}
+
+fn serve_stream(mut stream: TcpStream, peer: SocketAddr, daemon: Arc<Daemon>, opts: Arc<Options>) {
+    let id = source_id(&peer);
+    let mut remote = Remote::new();
+    let timeouts = stream.set_read_timeout(Some(LINK_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(LINK_TIMEOUT)));
+    if let Err(e) = timeouts {
+        warn!("Dropping {}: {}", peer, e);
+        return;
+    }
+
+    while !daemon.stopping.is_stopped() {
+        let data = match read_packet(&mut stream) {
+            Ok(d) => d,
+            Err(e) => {
+                info!("{} stopped sending: {}", peer, e);
+                break;
+            },
+        };
+        match net::decode(&data, opts.psk.as_deref()) {
+            // nothing gets lost over TCP, but a new session needs the nonce
+            Ok((header, packet)) => {
+                if remote.take(&daemon, &opts, &id, header, packet) {
+                    let resync = Packet::Resync { nonce: remote.feed.nonce() };
+                    if let Err(e) = write_packet(&mut stream, &net::encode(header, &resync, opts.psk.as_deref())) {
+                        info!("{} stopped listening: {}", peer, e);
+                        break;
+                    }
+                }
+            },
+            Err(e) => {
+                reject(&id, &e.to_string());
+                break;
+            },
+        }
+    }
+    remote.detach(&daemon);
+}
+
+pub fn serve_tcp(listener: TcpListener, daemon: Arc<Daemon>, opts: Arc<Options>) {
+    while wait_for_client(&listener, &daemon.stopping) {
+        let (stream, peer) = match listener.accept() {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed accepting a sender: {}", e);
+                continue;
+            },
+        };
+        let (daemon, opts) = (Arc::clone(&daemon), Arc::clone(&opts));
+        std::thread::spawn(move || serve_stream(stream, peer, daemon, opts));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sink::{net::NetSink, Sink},
+        source::{AxisRange, SourceCaps},
+    };
+    use evdev::{AbsoluteAxisType, InputEventKind, Key};
+    use std::{env, fs};
+
+    const STICK: AxisRange = AxisRange { min: -32768, max: 32767, fuzz: 16, flat: 128 };
+
+    fn fake_source() -> (EventSender, OpenedEventSource) {
+        let (tx, mut source) = OpenedEventSource::fake();
+        source.caps = SourceCaps::DpadAndAB;
+        source.axes = vec![(AbsoluteAxisType::ABS_X, STICK)];
+        (tx, source)
+    }
+
+    fn send_frame(tx: &EventSender, events: &[InputEvent]) {
+        for ev in events {
+            tx.send(*ev).unwrap();
+        }
+        tx.send(InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0)).unwrap();
+    }
+
+    fn key(k: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, k.code(), value)
+    }
+
+    fn recv_tcp(stream: &mut TcpStream) -> (Header, Packet) {
+        net::decode(&read_packet(stream).unwrap(), None).unwrap()
+    }
+
+    // Takes a TCP link from the sink and lets it in once it used the nonce
+    fn accept_tcp(listener: &TcpListener, feed: &mut Feed) -> (TcpStream, OpenedEventSource) {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (header, packet) = recv_tcp(&mut stream);
+        assert!(matches!(feed.take("net:test", header, packet), (None, true)));
+        let resync = Packet::Resync { nonce: feed.nonce() };
+        write_packet(&mut stream, &net::encode(header, &resync, None)).unwrap();
+        let (header, packet) = recv_tcp(&mut stream);
+        let pad = feed.take("net:test", header, packet).0.expect("state with the nonce creates a pad");
+        (stream, pad)
+    }
+
+    // What came out of the pad's source, without the SYN_REPORTs
+    fn drain(pad: &OpenedEventSource) -> Vec<(InputEventKind, i32)> {
+        std::iter::from_fn(|| pad.chan.try_recv().ok())
+            .filter(|e| e.event_type() != EventType::SYNCHRONIZATION)
+            .map(|e| (e.kind(), e.value()))
+            .collect()
+    }
+
+    #[test]
+    fn udp_link_recovers_lost_frames() {
+        let psk_file = env::temp_dir().join(format!("rinputer4-psk-{}", std::process::id()));
+        fs::write(&psk_file, "hunter2\n").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let (tx, source) = fake_source();
+        let mut sink = NetSink::new(source).unwrap();
+        sink.set_setting("psk_file", psk_file.to_str().unwrap()).unwrap();
+        sink.set_setting("peer", &socket.local_addr().unwrap().to_string()).unwrap();
+        let _ = fs::remove_file(&psk_file);
+
+        let mut buf = [0u8; 2048];
+        let mut recv = || {
+            let (n, from) = socket.recv_from(&mut buf).unwrap();
+            let (header, packet) = net::decode(&buf[..n], Some(b"hunter2")).unwrap();
+            (header, packet, from)
+        };
+
+        // full state goes out first, and sets up the pad once it carries the
+        // nonce
+        let mut feed = Feed::default();
+        let (header, packet, from) = recv();
+        assert!(matches!(feed.take("net:test", header, packet), (None, true)));
+        let resync = Packet::Resync { nonce: feed.nonce() };
+        socket.send_to(&net::encode(header, &resync, Some(b"hunter2")), from).unwrap();
+        let (header, packet, _) = recv();
+        let (pad, resync) = feed.take("net:test", header, packet);
+        let pad = pad.expect("state with the nonce creates a pad");
+        assert!(!resync);
+        assert_eq!(pad.name, "Test pad");
+        assert_eq!(pad.caps, SourceCaps::DpadAndAB);
+        assert_eq!(pad.axes, [(AbsoluteAxisType::ABS_X, STICK)]);
+
+        send_frame(&tx, &[key(Key::BTN_SOUTH, 1)]);
+        let (header, packet, _) = recv();
+        assert!(feed.take("net:test", header, packet).0.is_none());
+        assert_eq!(drain(&pad), [(InputEventKind::Key(Key::BTN_SOUTH), 1)]);
+
+        // the release gets lost, the next frame shows the gap
+        send_frame(&tx, &[key(Key::BTN_SOUTH, 0)]);
+        let (lost, _, _) = recv();
+        send_frame(&tx, &[InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 900)]);
+        let (header, packet, from) = recv();
+        assert_eq!(header.seq, lost.seq + 1);
+        let (_, resync) = feed.take("net:test", header, packet);
+        assert!(resync);
+        let resync = Packet::Resync { nonce: feed.nonce() };
+        socket.send_to(&net::encode(header, &resync, Some(b"hunter2")), from).unwrap();
+
+        let (header, packet, _) = recv();
+        assert!(matches!(packet, Packet::State { .. }));
+        feed.take("net:test", header, packet);
+        let seen = drain(&pad);
+        assert!(seen.contains(&(InputEventKind::Key(Key::BTN_SOUTH), 0)));
+        assert_eq!(seen.last(), Some(&(InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X), 900)));
+
+        // a sink going away lets go of everything right away
+        send_frame(&tx, &[key(Key::BTN_EAST, 1)]);
+        let (header, packet, _) = recv();
+        feed.take("net:test", header, packet);
+        drop(sink);
+        let (header, packet, _) = recv();
+        feed.take("net:test", header, packet);
+        let seen = drain(&pad);
+        assert!(seen.contains(&(InputEventKind::Key(Key::BTN_EAST), 0)));
+        assert!(seen.contains(&(InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X), 0)));
+    }
+
+    #[test]
+    fn replayed_states_are_ignored() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tx, source) = fake_source();
+        let mut sink = NetSink::new(source).unwrap();
+        sink.set_setting("transport", "tcp").unwrap();
+        sink.set_setting("peer", &listener.local_addr().unwrap().to_string()).unwrap();
+
+        // what someone listening in recorded
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut feed = Feed::default();
+        let first = read_packet(&mut stream).unwrap();
+        let (header, packet) = net::decode(&first, None).unwrap();
+        feed.take("net:test", header, packet);
+        let resync = Packet::Resync { nonce: feed.nonce() };
+        write_packet(&mut stream, &net::encode(header, &resync, None)).unwrap();
+        let accepted = read_packet(&mut stream).unwrap();
+        let (header, packet) = net::decode(&accepted, None).unwrap();
+        let pad = feed.take("net:test", header, packet).0.expect("state with the nonce creates a pad");
+        send_frame(&tx, &[key(Key::BTN_EAST, 1)]);
+        let live = read_packet(&mut stream).unwrap();
+        let (header, packet) = net::decode(&live, None).unwrap();
+        feed.take("net:test", header, packet);
+        assert_eq!(drain(&pad), [(InputEventKind::Key(Key::BTN_EAST), 1)]);
+
+        // old packets of this session don't get through
+        for data in [&first, &accepted, &live] {
+            let (header, packet) = net::decode(data, None).unwrap();
+            assert!(matches!(feed.take("net:test", header, packet), (None, false)));
+        }
+        assert!(drain(&pad).is_empty());
+
+        // and neither does the session once it ended, which gets reported
+        drop(sink);
+        let (_subscription, rx) = events::subscribe();
+        let mut feed = Feed::default();
+        for data in [&first, &accepted, &accepted, &accepted] {
+            let (header, packet) = net::decode(data, None).unwrap();
+            assert!(matches!(feed.take("net:replay", header, packet), (None, true)));
+        }
+        assert!(rx.try_iter().any(|ev| matches!(ev, Event::SenderRejected { source, .. } if source == "net:replay")));
+    }
+
+    #[test]
+    fn handshaking_senders_make_room_for_new_ones() {
+        let peer = |port: u16| SocketAddr::from(([192, 0, 2, 1], port));
+        let mut remotes: HashMap<SocketAddr, Remote> = (0..MAX_REMOTES as u16)
+            .map(|port| {
+                let mut remote = Remote::new();
+                remote.sink = Some(port as SinkId);
+                (peer(port), remote)
+            })
+            .collect();
+        // every sender has a pad, nobody gets kicked out for a newcomer
+        assert!(!make_room(&mut remotes));
+
+        remotes.get_mut(&peer(3)).unwrap().sink = None;
+        assert!(make_room(&mut remotes));
+        assert!(!remotes.contains_key(&peer(3)));
+        assert_eq!(remotes.len(), MAX_REMOTES - 1);
+    }
+
+    #[test]
+    fn tcp_link_sends_state_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tx, source) = fake_source();
+        let mut sink = NetSink::new(source).unwrap();
+        sink.set_setting("transport", "tcp").unwrap();
+        sink.set_setting("peer", &listener.local_addr().unwrap().to_string()).unwrap();
+
+        let mut feed = Feed::default();
+        let (mut stream, pad) = accept_tcp(&listener, &mut feed);
+        send_frame(&tx, &[key(Key::BTN_NORTH, 1)]);
+        let (header, packet) = recv_tcp(&mut stream);
+        assert!(!feed.take("net:test", header, packet).1);
+        assert_eq!(drain(&pad), [(InputEventKind::Key(Key::BTN_NORTH), 1)]);
+    }
+
+    #[test]
+    fn only_pad_input_goes_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tx, source) = fake_source();
+        let mut sink = NetSink::new(source).unwrap();
+        sink.set_setting("transport", "tcp").unwrap();
+        sink.set_setting("peer", &listener.local_addr().unwrap().to_string()).unwrap();
+
+        let mut feed = Feed::default();
+        let (mut stream, pad) = accept_tcp(&listener, &mut feed);
+
+        // hid-generic pads send the scancode along with every button
+        let scan = InputEvent::new(EventType::MISC, evdev::MiscType::MSC_SCAN.0, 0x90001);
+        send_frame(&tx, &[scan, key(Key::BTN_SOUTH, 1)]);
+        let (header, packet) = recv_tcp(&mut stream);
+        feed.take("net:test", header, packet);
+        assert_eq!(drain(&pad), [(InputEventKind::Key(Key::BTN_SOUTH), 1)]);
+    }
+}
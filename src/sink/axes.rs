@@ -0,0 +1,221 @@
+use evdev::{
+    AbsoluteAxisType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    Key,
+};
+
+use crate::{
+    sink::profile::Profile,
+    source::{remapped, AxisRange},
+};
+
+enum Mapping {
+    // sticks and hats, the middle of the source lands on the middle of the
+    // output and values within `flat` of it count as centered
+    Centered { from: AxisRange, to: AxisRange },
+    Linear { from: AxisRange, to: AxisRange },
+    // an analog trigger on a profile that only has a button for it
+    Button { key: Key, threshold: i32 },
+}
+
+fn is_centered(axis: AbsoluteAxisType) -> bool {
+    matches!(axis,
+        AbsoluteAxisType::ABS_X | AbsoluteAxisType::ABS_Y
+        | AbsoluteAxisType::ABS_RX | AbsoluteAxisType::ABS_RY
+        | AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y
+        | AbsoluteAxisType::ABS_HAT1X | AbsoluteAxisType::ABS_HAT1Y
+        | AbsoluteAxisType::ABS_HAT2X | AbsoluteAxisType::ABS_HAT2Y
+        | AbsoluteAxisType::ABS_HAT3X | AbsoluteAxisType::ABS_HAT3Y)
+}
+
+// Rest position of an axis, 128 for 0..255 and 0 for symmetric ranges
+fn center(r: &AxisRange) -> i64 {
+    r.min as i64 + (r.max as i64 - r.min as i64 + 1) / 2
+}
+
+// Where an axis of the output sits when nothing touches it
+pub fn rest(axis: AbsoluteAxisType, r: &AxisRange) -> i32 {
+    if is_centered(axis) {
+        center(r) as i32
+    } else {
+        r.min
+    }
+}
+
+fn scale(value: i64, from_len: i64, to_len: i64) -> i64 {
+    if from_len == 0 {
+        return 0;
+    }
+    value * to_len / from_len
+}
+
+// Deflection of a centered axis from -1.0 to 1.0, 0.0 within its flat area
+pub fn normalized(range: &AxisRange, value: i32) -> f64 {
+    let c = center(range);
+    let off = value as i64 - c;
+    let half = if off < 0 { c - range.min as i64 } else { range.max as i64 - c };
+    if off.abs() <= range.flat as i64 || half == 0 {
+        return 0.0;
+    }
+    (off as f64 / half as f64).clamp(-1.0, 1.0)
+}
+
+impl Mapping {
+    fn apply(&self, axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        let value = value as i64;
+        let out = match self {
+            Mapping::Centered { from, to } => {
+                let (c, oc) = (center(from), center(to));
+                let off = value - c;
+                let out = if off.abs() <= from.flat as i64 {
+                    oc
+                } else if off < 0 {
+                    oc + scale(off, c - from.min as i64, oc - to.min as i64)
+                } else {
+                    oc + scale(off, from.max as i64 - c, to.max as i64 - oc)
+                };
+                out.clamp(to.min as i64, to.max as i64)
+            },
+            Mapping::Linear { from, to } => {
+                let out = to.min as i64 + scale(value - from.min as i64,
+                    from.max as i64 - from.min as i64, to.max as i64 - to.min as i64);
+                out.clamp(to.min as i64, to.max as i64)
+            },
+            Mapping::Button { key, threshold } =>
+                return InputEvent::new(EventType::KEY, key.code(), (value > *threshold as i64) as i32),
+        };
+        InputEvent::new(EventType::ABSOLUTE, axis.0, out as i32)
+    }
+}
+
+// Rescales every axis of a source into the range the sink's profile
+// advertises. Axes the source didn't report a range for pass through. Fuzz
+// is left to the kernel, it filters both the source and the virtual pad.
+pub struct AxisMap {
+    axes: Vec<(AbsoluteAxisType, Mapping)>,
+}
+
+impl AxisMap {
+    pub fn new(source: &[(AbsoluteAxisType, AxisRange)], profile: &Profile) -> Self {
+        let mut axes = Vec::new();
+        for (axis, from) in source {
+            let mapping = match profile.axis(*axis) {
+                Some(to) if is_centered(*axis) => Mapping::Centered { from: *from, to },
+                Some(to) => Mapping::Linear { from: *from, to },
+                None => {
+                    let key = match *axis {
+                        AbsoluteAxisType::ABS_Z => Key::BTN_TL2,
+                        AbsoluteAxisType::ABS_RZ => Key::BTN_TR2,
+                        _ => continue,
+                    };
+                    if !profile.keys.contains(&key) {
+                        continue;
+                    }
+                    Mapping::Button { key, threshold: from.min + (from.max - from.min) / 2 }
+                },
+            };
+            axes.push((*axis, mapping));
+        }
+        Self { axes }
+    }
+
+    pub fn apply(&self, ev: InputEvent) -> InputEvent {
+        let axis = match ev.kind() {
+            InputEventKind::AbsAxis(axis) => axis,
+            _ => return ev,
+        };
+        let mapping = match self.axes.iter().find(|(a, _)| *a == axis) {
+            Some((_, m)) => m,
+            None => return ev,
+        };
+
+        remapped(mapping.apply(axis, ev.value()), &ev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::profile::{DUALSHOCK_4, SWITCH_PRO, XBOX_360, XBOX_SERIES};
+
+    const PS_STICK: AxisRange = AxisRange { min: 0, max: 255, fuzz: 0, flat: 0 };
+    const JOYCON_STICK: AxisRange = AxisRange { min: -32767, max: 32767, fuzz: 250, flat: 500 };
+    const TRIGGER: AxisRange = AxisRange { min: 0, max: 255, fuzz: 0, flat: 0 };
+
+    fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    fn value(map: &AxisMap, axis: AbsoluteAxisType, v: i32) -> i32 {
+        map.apply(abs(axis, v)).value()
+    }
+
+    #[test]
+    fn centers_unsigned_sticks() {
+        let map = AxisMap::new(&[(AbsoluteAxisType::ABS_X, PS_STICK)], &XBOX_360);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_X, 128), 0);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_X, 0), -32768);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_X, 255), 32767);
+        assert!(value(&map, AbsoluteAxisType::ABS_X, 192) > 16000);
+    }
+
+    #[test]
+    fn round_trips_back_to_unsigned() {
+        let xbox = AxisRange { min: -32768, max: 32767, fuzz: 16, flat: 0 };
+        let map = AxisMap::new(&[(AbsoluteAxisType::ABS_RY, xbox)], &DUALSHOCK_4);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_RY, 0), 128);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_RY, -32768), 0);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_RY, 32767), 255);
+    }
+
+    #[test]
+    fn flat_area_is_centered() {
+        let map = AxisMap::new(&[(AbsoluteAxisType::ABS_Y, JOYCON_STICK)], &XBOX_360);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_Y, 400), 0);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_Y, -500), 0);
+        assert_ne!(value(&map, AbsoluteAxisType::ABS_Y, 501), 0);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_Y, -32767), -32768);
+    }
+
+    #[test]
+    fn clamps_out_of_range_values() {
+        let map = AxisMap::new(&[(AbsoluteAxisType::ABS_X, PS_STICK)], &XBOX_360);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_X, 1000), 32767);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_X, -1000), -32768);
+    }
+
+    #[test]
+    fn stretches_triggers() {
+        let map = AxisMap::new(&[(AbsoluteAxisType::ABS_Z, TRIGGER)], &XBOX_SERIES);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_Z, 0), 0);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_Z, 255), 1023);
+    }
+
+    #[test]
+    fn digital_triggers_become_buttons() {
+        let map = AxisMap::new(&[(AbsoluteAxisType::ABS_RZ, TRIGGER)], &SWITCH_PRO);
+        let out = map.apply(abs(AbsoluteAxisType::ABS_RZ, 200));
+        assert_eq!(out.kind(), InputEventKind::Key(Key::BTN_TR2));
+        assert_eq!(out.value(), 1);
+        assert_eq!(map.apply(abs(AbsoluteAxisType::ABS_RZ, 10)).value(), 0);
+    }
+
+    #[test]
+    fn normalizes_deflection() {
+        assert_eq!(normalized(&PS_STICK, 128), 0.0);
+        assert_eq!(normalized(&PS_STICK, 0), -1.0);
+        assert_eq!(normalized(&PS_STICK, 255), 1.0);
+        assert_eq!(normalized(&JOYCON_STICK, 499), 0.0);
+        assert!((normalized(&JOYCON_STICK, 16384) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn unknown_axes_and_keys_pass_through() {
+        let map = AxisMap::new(&[], &XBOX_360);
+        assert_eq!(value(&map, AbsoluteAxisType::ABS_X, 77), 77);
+        let key = InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1);
+        assert_eq!(map.apply(key).kind(), InputEventKind::Key(Key::BTN_SOUTH));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    io,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+use evdev::{
+    uinput::{UInputEvent, VirtualDevice},
+    Device,
+    FFEffect,
+    FFEffectData,
+    FFEffectKind,
+    InputEventKind,
+    UInputEventType,
+};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    poll::{poll, PollFd, PollFlags},
+};
+use anyhow::{bail, Result};
+use log::{debug, warn};
+
+use crate::source::{StopFlag, POLL_INTERVAL};
+
+// How many effects a game can upload to a virtual pad at once
+pub const MAX_EFFECTS: u32 = 16;
+
+// Rumble settings of one sink, changed through the control socket while
+// the sink runs
+pub struct FfSettings {
+    enabled: AtomicBool,
+    // percent of the strength the game asked for
+    intensity: AtomicU32,
+}
+
+impl Default for FfSettings {
+    fn default() -> Self {
+        Self { enabled: AtomicBool::new(true), intensity: AtomicU32::new(100) }
+    }
+}
+
+impl FfSettings {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+    pub fn intensity(&self) -> u32 {
+        self.intensity.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "rumble" => match value {
+                "on" => self.enabled.store(true, Ordering::Relaxed),
+                "off" => self.enabled.store(false, Ordering::Relaxed),
+                _ => bail!("rumble is either on or off, not {}", value),
+            },
+            "rumble_intensity" => match value.parse::<u32>() {
+                Ok(v) if v <= 100 => self.intensity.store(v, Ordering::Relaxed),
+                _ => bail!("rumble_intensity is a percentage from 0 to 100, not {}", value),
+            },
+            _ => bail!("Unknown setting {}", key),
+        }
+        Ok(())
+    }
+}
+
+fn scaled(mut data: FFEffectData, percent: u32) -> FFEffectData {
+    let scale = |v: u16| (v as u32 * percent / 100) as u16;
+    if let FFEffectKind::Rumble { strong_magnitude, weak_magnitude } = data.kind {
+        data.kind = FFEffectKind::Rumble {
+            strong_magnitude: scale(strong_magnitude),
+            weak_magnitude: scale(weak_magnitude),
+        };
+    }
+    data
+}
+
+// An effect the game uploaded, mirrored on every physical device
+struct Effect {
+    data: FFEffectData,
+    // intensity the copies were uploaded with
+    percent: u32,
+    copies: Vec<FFEffect>,
+    playing: bool,
+}
+
+impl Effect {
+    fn rescale(&mut self, percent: u32) {
+        if self.percent == percent {
+            return;
+        }
+        for copy in self.copies.iter_mut() {
+            let _ = copy.update(scaled(self.data, percent));
+        }
+        self.percent = percent;
+    }
+
+    fn stop(&mut self) {
+        for copy in self.copies.iter_mut() {
+            let _ = copy.stop();
+        }
+        self.playing = false;
+    }
+}
+
+fn upload_to(targets: &mut [Device], data: FFEffectData, percent: u32) -> Vec<FFEffect> {
+    targets.iter_mut()
+        .filter_map(|dev| dev.upload_ff_effect(scaled(data, percent))
+            .map_err(|e| warn!("Failed to upload a rumble effect: {}", e))
+            .ok())
+        .collect()
+}
+
+// Effects a game uploaded to a virtual pad and the devices they are
+// mirrored on. Outlives the sources, a new one gets every effect again.
+pub struct Passthrough {
+    targets: Vec<Device>,
+    effects: HashMap<i16, Effect>,
+    settings: Arc<FfSettings>,
+}
+
+impl Passthrough {
+    pub fn new(settings: Arc<FfSettings>) -> Self {
+        Self { targets: Vec::new(), effects: HashMap::new(), settings }
+    }
+
+    // Mirrors effects on the devices at `nodes` from now on, the copies on
+    // the previous ones are erased
+    pub fn retarget(&mut self, nodes: &[PathBuf]) {
+        for effect in self.effects.values_mut() {
+            effect.copies.clear();
+            effect.playing = false;
+        }
+        self.targets = nodes.iter()
+            .filter_map(|node| Device::open(node)
+                .map_err(|e| warn!("Can't open {} for rumble: {}", node.display(), e))
+                .ok())
+            .collect();
+
+        let percent = self.settings.intensity();
+        for effect in self.effects.values_mut() {
+            effect.copies = upload_to(&mut self.targets, effect.data, percent);
+            effect.percent = percent;
+        }
+    }
+    fn upload(&mut self, id: i16, data: FFEffectData) {
+        let percent = self.settings.intensity();
+        if let Some(effect) = self.effects.get_mut(&id) {
+            // the game changed an effect it already uploaded
+            effect.data = data;
+            for copy in effect.copies.iter_mut() {
+                let _ = copy.update(scaled(data, percent));
+            }
+            effect.percent = percent;
+            return;
+        }
+
+        let copies = upload_to(&mut self.targets, data, percent);
+        self.effects.insert(id, Effect { data, percent, copies, playing: false });
+    }
+
+    fn play(&mut self, id: i16, count: i32) {
+        let settings = Arc::clone(&self.settings);
+        let effect = match self.effects.get_mut(&id) {
+            Some(e) => e,
+            None => return,
+        };
+        if count == 0 || !settings.enabled() {
+            effect.stop();
+            return;
+        }
+        effect.rescale(settings.intensity());
+        for copy in effect.copies.iter_mut() {
+            let _ = copy.play(count);
+        }
+        effect.playing = true;
+    }
+
+    fn stop_all(&mut self) {
+        for effect in self.effects.values_mut().filter(|e| e.playing) {
+            effect.stop();
+        }
+    }
+
+    fn handle(&mut self, dev: &Mutex<VirtualDevice>, ev: UInputEvent) -> Result<()> {
+        match ev.kind() {
+            InputEventKind::UInput(code) if code == UInputEventType::UI_FF_UPLOAD.0 => {
+                let mut dev = dev.lock().unwrap();
+                let upload = dev.process_ff_upload(ev)?;
+                self.upload(upload.effect_id(), upload.effect());
+                // dropping the upload tells the kernel it went through
+            },
+            InputEventKind::UInput(code) if code == UInputEventType::UI_FF_ERASE.0 => {
+                let mut dev = dev.lock().unwrap();
+                let erase = dev.process_ff_erase(ev)?;
+                // dropping the copies erases them from the physical devices
+                self.effects.remove(&(erase.effect_id() as i16));
+            },
+            InputEventKind::ForceFeedback(id) => self.play(id as i16, ev.value()),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+// Forwards what games do with the rumble of a virtual pad to the targets
+// of `ff`, handing it back once stopped. The kernel waits for uploads and
+// erases to be answered, so this gets its own thread instead of waiting
+// behind source events.
+pub fn ff_worker(dev: Arc<Mutex<VirtualDevice>>, mut ff: Passthrough, stop: StopFlag) -> Passthrough {
+    let fd = dev.lock().unwrap().as_raw_fd();
+    // reads happen under the lock the sink writes with, they must not block
+    if let Err(e) = fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
+        warn!("Rumble passthrough disabled: {}", e);
+        return ff;
+    }
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+
+    while !stop.is_stopped() {
+        if !ff.settings.enabled() {
+            ff.stop_all();
+        }
+        match poll(&mut fds, POLL_INTERVAL.as_millis() as i32) {
+            Ok(0) => continue,
+            Ok(_) => (),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+
+        let events: Vec<UInputEvent> = match dev.lock().unwrap().fetch_events() {
+            Ok(events) => events.collect(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(_) => break,
+        };
+        for ev in events {
+            if let Err(e) = ff.handle(&dev, ev) {
+                debug!("Failed to handle a rumble request: {}", e);
+            }
+        }
+    }
+    ff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rumble(strong: u16, weak: u16) -> FFEffectData {
+        FFEffectData {
+            direction: 0,
+            trigger: Default::default(),
+            replay: Default::default(),
+            kind: FFEffectKind::Rumble { strong_magnitude: strong, weak_magnitude: weak },
+        }
+    }
+
+    #[test]
+    fn scales_rumble_magnitudes() {
+        assert_eq!(scaled(rumble(0xffff, 1000), 50).kind, rumble(0x7fff, 500).kind);
+        assert_eq!(scaled(rumble(0xffff, 1000), 100).kind, rumble(0xffff, 1000).kind);
+        assert_eq!(scaled(rumble(0xffff, 1000), 0).kind, rumble(0, 0).kind);
+    }
+
+    #[test]
+    fn validates_settings() {
+        let settings = FfSettings::default();
+        assert!(settings.enabled());
+        settings.set("rumble", "off").unwrap();
+        assert!(!settings.enabled());
+        settings.set("rumble_intensity", "40").unwrap();
+        assert_eq!(settings.intensity(), 40);
+
+        assert!(settings.set("rumble", "maybe").is_err());
+        assert!(settings.set("rumble_intensity", "101").is_err());
+        assert!(settings.set("rumble_intensity", "-5").is_err());
+        assert!(settings.set("volume", "3").is_err());
+        assert_eq!(settings.intensity(), 40);
+    }
+}
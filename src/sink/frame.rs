@@ -0,0 +1,132 @@
+use std::{
+    io,
+    os::unix::io::AsRawFd,
+};
+use evdev::{
+    EventType,
+    InputEvent,
+    Synchronization,
+};
+use nix::libc::input_event;
+
+// Events up to the SYN_REPORT ending them. Readers only look at the state
+// a frame leaves behind, so an axis keeps just its last value and a key
+// that is set to what it already was within the frame is dropped.
+#[derive(Default)]
+pub struct Frame {
+    events: Vec<InputEvent>,
+}
+
+impl Frame {
+    pub fn push(&mut self, ev: InputEvent) {
+        let same = |e: &InputEvent| e.event_type() == ev.event_type() && e.code() == ev.code();
+        match ev.event_type() {
+            // the caller ends frames
+            EventType::SYNCHRONIZATION => return,
+            EventType::ABSOLUTE => self.events.retain(|e| !same(e)),
+            // a press and release within one frame is still a click
+            EventType::KEY if self.events.iter().rev().find(|e| same(e)).is_some_and(|e| e.value() == ev.value()) =>
+                return,
+            _ => (),
+        }
+        self.events.push(ev);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn take(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+// Writes `events` and a SYN_REPORT to a uinput device. VirtualDevice::emit
+// writes the SYN_REPORT separately, this needs a single write. Returns how
+// many writes that took.
+pub fn write_frame(dev: &impl AsRawFd, events: &[InputEvent]) -> io::Result<u64> {
+    let mut raw: Vec<input_event> = events.iter().map(|ev| *ev.as_ref()).collect();
+    raw.push(*InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0).as_ref());
+    // SAFETY: input_event is plain old data
+    let bytes = unsafe {
+        std::slice::from_raw_parts(raw.as_ptr() as *const u8, std::mem::size_of_val(raw.as_slice()))
+    };
+    write_counted(bytes, |buf| nix::unistd::write(dev.as_raw_fd(), buf).map_err(io::Error::from))
+}
+
+// Like write_all, but returns how many calls to `write` it took
+pub fn write_counted(mut bytes: &[u8], mut write: impl FnMut(&[u8]) -> io::Result<usize>) -> io::Result<u64> {
+    let mut writes = 0;
+    while !bytes.is_empty() {
+        writes += 1;
+        match write(bytes) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "short write")),
+            Ok(n) => bytes = &bytes[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(writes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{AbsoluteAxisType, InputEventKind, Key};
+
+    fn values(frame: &mut Frame) -> Vec<(InputEventKind, i32)> {
+        frame.take().iter().map(|e| (e.kind(), e.value())).collect()
+    }
+
+    #[test]
+    fn keeps_last_axis_value() {
+        let mut frame = Frame::default();
+        frame.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 10));
+        frame.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, 5));
+        frame.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 20));
+        frame.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+        assert_eq!(values(&mut frame), [
+            (InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Y), 5),
+            (InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X), 20),
+        ]);
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn drops_repeated_key_states_only() {
+        let mut frame = Frame::default();
+        frame.push(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1));
+        frame.push(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1));
+        frame.push(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 0));
+        assert_eq!(values(&mut frame), [
+            (InputEventKind::Key(Key::BTN_SOUTH), 1),
+            (InputEventKind::Key(Key::BTN_SOUTH), 0),
+        ]);
+    }
+
+    #[test]
+    fn writes_frame_and_syn_at_once() {
+        let (rx, tx) = nix::unistd::pipe().unwrap();
+        let tx = unsafe { <std::fs::File as std::os::unix::io::FromRawFd>::from_raw_fd(tx) };
+        let events = [InputEvent::new(EventType::KEY, Key::BTN_EAST.code(), 1)];
+        assert_eq!(write_frame(&tx, &events).unwrap(), 1);
+
+        let mut buf = [0u8; 256];
+        let n = nix::unistd::read(rx, &mut buf).unwrap();
+        assert_eq!(n, 2 * std::mem::size_of::<input_event>());
+        nix::unistd::close(rx).unwrap();
+    }
+
+    #[test]
+    fn counts_every_write_of_a_short_written_frame() {
+        let mut out = Vec::new();
+        let writes = write_counted(&[1; 60], |buf| {
+            let n = buf.len().min(24);
+            out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        });
+        assert_eq!(writes.unwrap(), 3);
+        assert_eq!(out, [1; 60]);
+        assert!(write_counted(&[1; 8], |_| Ok(0)).is_err());
+    }
+}
@@ -0,0 +1,625 @@
+use crate::{
+    monitor::{self, Stage},
+    sink::{
+        axes,
+        frame::write_frame,
+        stats::{SinkStats, StatsSnapshot},
+        Sink,
+    },
+    source::{remapped, AxisRange, OpenedEventSource, SourceCaps, SourceIdentity, StopFlag, POLL_INTERVAL},
+};
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use evdev::{
+    uinput::{
+        VirtualDeviceBuilder,
+        VirtualDevice,
+    },
+    AbsoluteAxisType,
+    AttributeSet,
+    BusType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    InputId,
+    Key,
+    RelativeAxisType,
+};
+use anyhow::{bail, Result};
+use log::{info, trace};
+
+// How often the pointer moves while a stick is held
+static TICK: Duration = Duration::from_millis(10);
+// Deflection ignored on top of the source's flat area, most pads don't
+// report one and their sticks never rest exactly in the middle
+const DEADZONE: f64 = 0.12;
+// One wheel notch in REL_WHEEL_HI_RES units
+const NOTCH: i32 = 120;
+// Sources that didn't report a range get the one of an Xbox stick
+const DEFAULT_STICK: AxisRange = AxisRange { min: -32768, max: 32767, fuzz: 16, flat: 128 };
+
+const DEFAULT_MAP: &[(Key, Key)] = &[
+    (Key::BTN_SOUTH, Key::BTN_LEFT),
+    (Key::BTN_EAST, Key::BTN_RIGHT),
+    (Key::BTN_WEST, Key::KEY_SPACE),
+    (Key::BTN_NORTH, Key::KEY_ENTER),
+    (Key::BTN_TL, Key::BTN_SIDE),
+    (Key::BTN_TR, Key::BTN_EXTRA),
+    (Key::BTN_TL2, Key::KEY_PAGEUP),
+    (Key::BTN_TR2, Key::KEY_PAGEDOWN),
+    (Key::BTN_SELECT, Key::KEY_TAB),
+    (Key::BTN_START, Key::KEY_ESC),
+    (Key::BTN_MODE, Key::KEY_LEFTMETA),
+    (Key::BTN_THUMBL, Key::KEY_LEFTSHIFT),
+    (Key::BTN_THUMBR, Key::BTN_MIDDLE),
+    (Key::BTN_DPAD_UP, Key::KEY_UP),
+    (Key::BTN_DPAD_DOWN, Key::KEY_DOWN),
+    (Key::BTN_DPAD_LEFT, Key::KEY_LEFT),
+    (Key::BTN_DPAD_RIGHT, Key::KEY_RIGHT),
+];
+
+// everything on a regular keyboard plus the mouse buttons
+fn can_emit(key: Key) -> bool {
+    (Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code()).contains(&key.code())
+        || (Key::BTN_LEFT.code()..=Key::BTN_TASK.code()).contains(&key.code())
+}
+
+// "btn_south" for BTN_SOUTH, as used in settings
+fn key_name(key: Key) -> String {
+    format!("{:?}", key).to_lowercase()
+}
+
+struct Settings {
+    // what each pad button presses, None for nothing
+    map: BTreeMap<Key, Option<Key>>,
+    // pixels per second with the stick fully deflected
+    pointer_speed: u32,
+    // wheel notches per second with the stick fully deflected
+    scroll_speed: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            map: DEFAULT_MAP.iter().map(|(from, to)| (*from, Some(*to))).collect(),
+            pointer_speed: 1200,
+            scroll_speed: 20,
+        }
+    }
+}
+
+impl Settings {
+    fn to_map(&self) -> BTreeMap<String, String> {
+        let mut ret: BTreeMap<String, String> = self.map.iter()
+            .map(|(from, to)| (key_name(*from), to.map_or("none".to_string(), key_name)))
+            .collect();
+        ret.insert("pointer_speed".to_string(), self.pointer_speed.to_string());
+        ret.insert("scroll_speed".to_string(), self.scroll_speed.to_string());
+        ret
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "pointer_speed" => match value.parse::<u32>() {
+                Ok(v) if (1..=10000).contains(&v) => self.pointer_speed = v,
+                _ => bail!("pointer_speed is in pixels per second from 1 to 10000, not {}", value),
+            },
+            "scroll_speed" => match value.parse::<u32>() {
+                Ok(v) if (1..=100).contains(&v) => self.scroll_speed = v,
+                _ => bail!("scroll_speed is in notches per second from 1 to 100, not {}", value),
+            },
+            _ => {
+                let button = match self.map.keys().find(|k| key_name(**k) == key) {
+                    Some(k) => *k,
+                    None => bail!("Unknown setting {}", key),
+                };
+                let target = if value == "none" {
+                    None
+                } else {
+                    match Key::from_str(&value.to_uppercase()) {
+                        Ok(k) if can_emit(k) => Some(k),
+                        _ => bail!("{} is not a keyboard key or mouse button", value),
+                    }
+                };
+                self.map.insert(button, target);
+            },
+        }
+        Ok(())
+    }
+}
+
+// Stick deflection to speed, -1.0 to 1.0 on each axis. Nothing happens
+// inside the dead zone and speed grows with the square of the deflection
+// past it, so small movements stay precise.
+fn curve(x: f64, y: f64) -> (f64, f64) {
+    let m = x.hypot(y);
+    if m <= DEADZONE {
+        return (0.0, 0.0);
+    }
+    let speed = ((m.min(1.0) - DEADZONE) / (1.0 - DEADZONE)).powi(2);
+    (x / m * speed, y / m * speed)
+}
+
+// Sums up fractional movement until there's a whole unit to send
+#[derive(Default)]
+struct Motion {
+    rest: f64,
+}
+
+impl Motion {
+    fn step(&mut self, v: f64) -> i32 {
+        self.rest += v;
+        let whole = self.rest.trunc();
+        self.rest -= whole;
+        whole as i32
+    }
+}
+
+fn key_event(key: Key, value: i32) -> InputEvent {
+    InputEvent::new(EventType::KEY, key.code(), value)
+}
+
+fn rel_event(axis: RelativeAxisType, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE, axis.0, value)
+}
+
+// Turns pad events into keyboard and mouse ones
+struct State {
+    ranges: Vec<(AbsoluteAxisType, AxisRange)>,
+    // left x, left y, right x, right y
+    sticks: [f64; 4],
+    pointer: [Motion; 2],
+    scroll: [Motion; 2],
+    // hi-res scroll not yet sent as whole notches
+    notches: [i32; 2],
+    hat: [i32; 2],
+    // pad buttons held down and what they pressed
+    pressed: BTreeMap<Key, Key>,
+}
+
+impl State {
+    fn new(ranges: Vec<(AbsoluteAxisType, AxisRange)>) -> Self {
+        Self {
+            ranges,
+            sticks: [0.0; 4],
+            pointer: Default::default(),
+            scroll: Default::default(),
+            notches: [0; 2],
+            hat: [0; 2],
+            pressed: BTreeMap::new(),
+        }
+    }
+
+    fn range(&self, axis: AbsoluteAxisType) -> AxisRange {
+        self.ranges.iter()
+            .find(|(a, _)| *a == axis)
+            .map_or(DEFAULT_STICK, |(_, r)| *r)
+    }
+
+    fn moving(&self) -> bool {
+        curve(self.sticks[0], self.sticks[1]) != (0.0, 0.0)
+            || curve(self.sticks[2], self.sticks[3]) != (0.0, 0.0)
+    }
+
+    fn button(&mut self, button: Key, down: bool, settings: &Settings, out: &mut Vec<InputEvent>) {
+        if down {
+            if self.pressed.contains_key(&button) {
+                return;
+            }
+            if let Some(Some(key)) = settings.map.get(&button) {
+                self.pressed.insert(button, *key);
+                out.push(key_event(*key, 1));
+            }
+        } else if let Some(key) = self.pressed.remove(&button) {
+            // the mapping may have changed since, release what was pressed
+            out.push(key_event(key, 0));
+        }
+    }
+
+    fn hat(&mut self, idx: usize, value: i32, neg: Key, pos: Key, settings: &Settings, out: &mut Vec<InputEvent>) {
+        let value = value.signum();
+        if self.hat[idx] == value {
+            return;
+        }
+        self.button(neg, value < 0, settings, out);
+        self.button(pos, value > 0, settings, out);
+        self.hat[idx] = value;
+    }
+
+    fn handle(&mut self, ev: InputEvent, settings: &Settings) -> Vec<InputEvent> {
+        let mut out = Vec::new();
+        match ev.kind() {
+            InputEventKind::Key(key) => self.button(key, ev.value() != 0, settings, &mut out),
+            InputEventKind::AbsAxis(axis) => match axis {
+                AbsoluteAxisType::ABS_X => self.sticks[0] = axes::normalized(&self.range(axis), ev.value()),
+                AbsoluteAxisType::ABS_Y => self.sticks[1] = axes::normalized(&self.range(axis), ev.value()),
+                AbsoluteAxisType::ABS_RX => self.sticks[2] = axes::normalized(&self.range(axis), ev.value()),
+                AbsoluteAxisType::ABS_RY => self.sticks[3] = axes::normalized(&self.range(axis), ev.value()),
+                AbsoluteAxisType::ABS_HAT0X =>
+                    self.hat(0, ev.value(), Key::BTN_DPAD_LEFT, Key::BTN_DPAD_RIGHT, settings, &mut out),
+                AbsoluteAxisType::ABS_HAT0Y =>
+                    self.hat(1, ev.value(), Key::BTN_DPAD_UP, Key::BTN_DPAD_DOWN, settings, &mut out),
+                AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ => {
+                    let r = self.range(axis);
+                    let button = if axis == AbsoluteAxisType::ABS_Z { Key::BTN_TL2 } else { Key::BTN_TR2 };
+                    self.button(button, ev.value() > r.min + (r.max - r.min) / 2, settings, &mut out);
+                },
+                _ => (),
+            },
+            _ => (),
+        }
+        out.into_iter().map(|new| remapped(new, &ev)).collect()
+    }
+
+    // Movement for `dt` seconds of the sticks held where they are
+    fn tick(&mut self, settings: &Settings, dt: f64) -> Vec<InputEvent> {
+        let mut out = Vec::new();
+
+        let (x, y) = curve(self.sticks[0], self.sticks[1]);
+        let speed = settings.pointer_speed as f64 * dt;
+        let dx = self.pointer[0].step(x * speed);
+        let dy = self.pointer[1].step(y * speed);
+        if dx != 0 {
+            out.push(rel_event(RelativeAxisType::REL_X, dx));
+        }
+        if dy != 0 {
+            out.push(rel_event(RelativeAxisType::REL_Y, dy));
+        }
+
+        // pushing the stick up scrolls up, which is a positive wheel value
+        let (x, y) = curve(self.sticks[2], self.sticks[3]);
+        let speed = (settings.scroll_speed * NOTCH as u32) as f64 * dt;
+        let wheels = [
+            (x, RelativeAxisType::REL_HWHEEL_HI_RES, RelativeAxisType::REL_HWHEEL),
+            (-y, RelativeAxisType::REL_WHEEL_HI_RES, RelativeAxisType::REL_WHEEL),
+        ];
+        for (i, (v, hi_res, wheel)) in wheels.into_iter().enumerate() {
+            let units = self.scroll[i].step(v * speed);
+            if units == 0 {
+                continue;
+            }
+            out.push(rel_event(hi_res, units));
+            self.notches[i] += units;
+            let whole = self.notches[i] / NOTCH;
+            if whole != 0 {
+                self.notches[i] -= whole * NOTCH;
+                out.push(rel_event(wheel, whole));
+            }
+        }
+        out
+    }
+
+    fn release_all(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.pressed).into_values()
+            .map(|key| key_event(key, 0))
+            .collect()
+    }
+}
+
+// Spaces out movement by TICK however often source events come in
+struct Pacer {
+    next_tick: Instant,
+}
+
+impl Pacer {
+    // How long to wait for source events at `now` before movement is due
+    fn timeout(&self, state: &State, now: Instant) -> Duration {
+        if state.moving() {
+            self.next_tick.saturating_duration_since(now)
+        } else {
+            POLL_INTERVAL
+        }
+    }
+
+    // What `ev` and the movement due at `now` make
+    fn step(&mut self, state: &mut State, ev: Option<InputEvent>, settings: &Settings, now: Instant) -> Vec<InputEvent> {
+        let mut out = Vec::new();
+        if let Some(ev) = ev {
+            let was_moving = state.moving();
+            out = state.handle(ev, settings);
+            if !was_moving {
+                self.next_tick = now + TICK;
+            }
+        }
+        // a held stick may keep sending events faster than TICK
+        if state.moving() && now >= self.next_tick {
+            self.next_tick += TICK;
+            out.extend(state.tick(settings, TICK.as_secs_f64()));
+        }
+        out
+    }
+}
+
+// Hands what source events and held sticks make to `emit` until the source
+// goes away, `stop` is set or `emit` returns false
+fn pump(src: &OpenedEventSource, state: &mut State, settings: &Mutex<Settings>, stop: &StopFlag,
+    mut emit: impl FnMut(&[InputEvent]) -> bool)
+{
+    let mut pacer = Pacer { next_tick: Instant::now() };
+
+    while !stop.is_stopped() {
+        let ev = match src.chan.recv_timeout(pacer.timeout(state, Instant::now())) {
+            Ok(ev) => Some(ev),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let out = pacer.step(state, ev, &settings.lock().unwrap(), Instant::now());
+        if !out.is_empty() && !emit(&out) {
+            break;
+        }
+    }
+}
+
+fn kbm_worker(src: OpenedEventSource, dst: VirtualDevice, settings: Arc<Mutex<Settings>>, stop: StopFlag, lost: Arc<AtomicBool>, stats: Arc<SinkStats>) {
+    let mut state = State::new(src.axes.clone());
+
+    pump(&src, &mut state, &settings, &stop, |out| {
+        match write_frame(&dst, out) {
+            Ok(writes) => stats.record_frame(writes),
+            Err(_) => return false,
+        }
+        for ev in out {
+            stats.record(ev);
+            trace!("{} -> {:?}", src.id, ev);
+            monitor::publish(Stage::Output, &src.id, ev);
+        }
+        true
+    });
+
+    if !stop.is_stopped() {
+        info!("Source {} of a sink went away", src.id);
+        lost.store(true, Ordering::Relaxed);
+    }
+
+    // don't leave keys stuck down on whatever had focus
+    let released = state.release_all();
+    if !released.is_empty() {
+        let _ = write_frame(&dst, &released);
+    }
+
+    drop(dst);
+    drop(src);
+}
+
+pub struct KbmSink {
+    source_id: String,
+    source_name: String,
+    source_caps: SourceCaps,
+    source_devices: Vec<SourceIdentity>,
+    source_lost: Arc<AtomicBool>,
+    lost_reported: AtomicBool,
+    stop: StopFlag,
+    stats: Arc<SinkStats>,
+    settings: Arc<Mutex<Settings>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for KbmSink {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Sink for KbmSink {
+    fn name(&self) -> &'static str {
+        "Keyboard and mouse"
+    }
+    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        let mut keys = AttributeSet::new();
+        for code in (Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code()).chain(Key::BTN_LEFT.code()..=Key::BTN_TASK.code()) {
+            keys.insert(Key::new(code));
+        }
+        let mut rel = AttributeSet::new();
+        for axis in [
+            RelativeAxisType::REL_X, RelativeAxisType::REL_Y,
+            RelativeAxisType::REL_WHEEL, RelativeAxisType::REL_HWHEEL,
+            RelativeAxisType::REL_WHEEL_HI_RES, RelativeAxisType::REL_HWHEEL_HI_RES,
+        ] {
+            rel.insert(axis);
+        }
+
+        let name = format!("{} (keyboard and mouse)", source.name);
+        let uinput_handle = VirtualDeviceBuilder::new()?
+            .name(name.as_bytes())
+            .input_id(InputId::new(BusType::BUS_VIRTUAL, 0, 0, 1))
+            .with_keys(&keys)?
+            .with_relative_axes(&rel)?
+            .build()?;
+
+        let stop = StopFlag::default();
+        let stop2 = stop.clone();
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost2 = Arc::clone(&lost);
+        let stats = Arc::new(SinkStats::new(source.chan.depth()));
+        let stats2 = Arc::clone(&stats);
+        let settings = Arc::new(Mutex::new(Settings::default()));
+        let settings2 = Arc::clone(&settings);
+
+        let mut out = Box::new(KbmSink {
+            source_id: source.id.clone(),
+            source_name: source.name.clone(),
+            source_caps: source.caps,
+            source_devices: source.devices.clone(),
+            source_lost: lost,
+            lost_reported: AtomicBool::new(false),
+            stop,
+            stats,
+            settings,
+            worker: None,
+        });
+
+        out.worker = Some(std::thread::spawn(move || kbm_worker(source, uinput_handle, settings2, stop2, lost2, stats2)));
+        Ok(out)
+    }
+    fn source_name(&self) -> String {
+        self.source_name.clone()
+    }
+    fn source_id(&self) -> String {
+        self.source_id.clone()
+    }
+    fn source_caps(&self) -> SourceCaps {
+        self.source_caps
+    }
+    fn source_devices(&self) -> Vec<SourceIdentity> {
+        self.source_devices.clone()
+    }
+    fn take_source_lost(&self) -> bool {
+        self.source_lost.load(Ordering::Relaxed) && !self.lost_reported.swap(true, Ordering::Relaxed)
+    }
+    fn is_healthy(&self) -> bool {
+        let finished = self.worker.as_ref().is_none_or(|w| w.is_finished());
+        !finished || self.source_lost.load(Ordering::Relaxed)
+    }
+    fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+    fn settings(&self) -> BTreeMap<String, String> {
+        self.settings.lock().unwrap().to_map()
+    }
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<()> {
+        self.settings.lock().unwrap().set(key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(k: Key, value: i32) -> InputEvent {
+        key_event(k, value)
+    }
+
+    fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    fn kinds(events: &[InputEvent]) -> Vec<(InputEventKind, i32)> {
+        events.iter().map(|e| (e.kind(), e.value())).collect()
+    }
+
+    #[test]
+    fn curve_has_dead_zone_and_accelerates() {
+        assert_eq!(curve(0.1, 0.0), (0.0, 0.0));
+        assert_eq!(curve(1.0, 0.0), (1.0, 0.0));
+        let (half, _) = curve(0.56, 0.0);
+        assert!(half > 0.2 && half < 0.3);
+        let (x, y) = curve(0.0, -2.0);
+        assert_eq!((x, y), (0.0, -1.0));
+    }
+
+    #[test]
+    fn motion_keeps_fractions() {
+        let mut m = Motion::default();
+        let sum: i32 = (0..10).map(|_| m.step(0.35)).sum();
+        assert_eq!(sum, 3);
+        let sum: i32 = (0..10).map(|_| m.step(-0.35)).sum();
+        assert_eq!(sum, -3);
+    }
+
+    #[test]
+    fn maps_buttons_and_releases_what_was_pressed() {
+        let mut settings = Settings::default();
+        let mut state = State::new(Vec::new());
+        let out = state.handle(key(Key::BTN_SOUTH, 1), &settings);
+        assert_eq!(kinds(&out), [(InputEventKind::Key(Key::BTN_LEFT), 1)]);
+
+        settings.set("btn_south", "key_a").unwrap();
+        let out = state.handle(key(Key::BTN_SOUTH, 0), &settings);
+        assert_eq!(kinds(&out), [(InputEventKind::Key(Key::BTN_LEFT), 0)]);
+
+        settings.set("btn_north", "none").unwrap();
+        assert!(state.handle(key(Key::BTN_NORTH, 1), &settings).is_empty());
+    }
+
+    #[test]
+    fn hat_and_triggers_become_keys() {
+        let settings = Settings::default();
+        let mut state = State::new(vec![(AbsoluteAxisType::ABS_Z, AxisRange { min: 0, max: 255, fuzz: 0, flat: 0 })]);
+        let out = state.handle(abs(AbsoluteAxisType::ABS_HAT0X, -1), &settings);
+        assert_eq!(kinds(&out), [(InputEventKind::Key(Key::KEY_LEFT), 1)]);
+        let out = state.handle(abs(AbsoluteAxisType::ABS_HAT0X, 1), &settings);
+        assert_eq!(kinds(&out), [(InputEventKind::Key(Key::KEY_LEFT), 0), (InputEventKind::Key(Key::KEY_RIGHT), 1)]);
+        let out = state.handle(abs(AbsoluteAxisType::ABS_Z, 200), &settings);
+        assert_eq!(kinds(&out), [(InputEventKind::Key(Key::KEY_PAGEUP), 1)]);
+        assert!(state.handle(abs(AbsoluteAxisType::ABS_Z, 220), &settings).is_empty());
+
+        assert_eq!(state.release_all().len(), 2);
+    }
+
+    #[test]
+    fn scrolls_in_hi_res_units_and_notches() {
+        let settings = Settings::default();
+        let mut state = State::new(Vec::new());
+        state.handle(abs(AbsoluteAxisType::ABS_RY, -32768), &settings);
+        assert!(state.moving());
+
+        let mut hi_res = 0;
+        let mut notches = 0;
+        // one second at full deflection
+        for _ in 0..100 {
+            for ev in state.tick(&settings, 0.01) {
+                match ev.kind() {
+                    InputEventKind::RelAxis(RelativeAxisType::REL_WHEEL_HI_RES) => hi_res += ev.value(),
+                    InputEventKind::RelAxis(RelativeAxisType::REL_WHEEL) => notches += ev.value(),
+                    _ => (),
+                }
+            }
+        }
+        assert_eq!(notches, settings.scroll_speed as i32);
+        assert_eq!(hi_res, notches * NOTCH);
+    }
+
+    #[test]
+    fn pointer_moves_while_events_keep_coming() {
+        let settings = Settings::default();
+        let mut state = State::new(Vec::new());
+        let start = Instant::now();
+        let mut pacer = Pacer { next_tick: start };
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // left stick held right, the right stick jittering in its dead zone
+        // every millisecond, far more often than TICK
+        let mut out = pacer.step(&mut state, Some(abs(AbsoluteAxisType::ABS_X, 32767)), &settings, start);
+        assert_eq!(pacer.timeout(&state, at(4)), Duration::from_millis(6));
+        for ms in 1..=200 {
+            let jitter = if ms % 2 == 0 { 100 } else { -100 };
+            out.extend(pacer.step(&mut state, Some(abs(AbsoluteAxisType::ABS_RX, jitter)), &settings, at(ms)));
+        }
+        // nothing comes in for a while, the stick is still held
+        out.extend(pacer.step(&mut state, None, &settings, at(210)));
+
+        let moved: Vec<i32> = out.iter()
+            .filter(|e| e.kind() == InputEventKind::RelAxis(RelativeAxisType::REL_X))
+            .map(|e| e.value())
+            .collect();
+        // 1200 pixels per second, one step every TICK
+        assert_eq!(moved, [12; 21]);
+    }
+
+    #[test]
+    fn validates_settings() {
+        let mut settings = Settings::default();
+        settings.set("pointer_speed", "800").unwrap();
+        assert_eq!(settings.to_map()["pointer_speed"], "800");
+        assert_eq!(settings.to_map()["btn_dpad_up"], "key_up");
+
+        assert!(settings.set("pointer_speed", "0").is_err());
+        assert!(settings.set("scroll_speed", "fast").is_err());
+        assert!(settings.set("btn_south", "abs_x").is_err());
+        assert!(settings.set("btn_south", "btn_south").is_err());
+        assert!(settings.set("key_a", "key_b").is_err());
+    }
+}
@@ -1,19 +1,220 @@
-use crate::{OpenedEventSource, source::SourceCaps};
+use crate::{OpenedEventSource, source::{leds, SourceCaps, SourceIdentity}};
 
-use anyhow::Result;
+use std::{collections::BTreeMap, path::Path, sync::mpsc::Receiver};
+use anyhow::{anyhow, bail, Result};
+use log::warn;
 
 pub mod uinput;
+pub mod profile;
+pub mod axes;
+pub mod ff;
+pub mod frame;
+pub mod kbm;
+pub mod motion;
+pub mod net;
+pub mod state;
+pub mod stats;
 use uinput::UinputSink;
+use kbm::KbmSink;
+use net::NetSink;
+use stats::StatsSnapshot;
+
+pub type NewSinkFn = fn(OpenedEventSource) -> Result<Box<dyn Sink>>;
+pub type SinkId = u64;
+
+// Hears from a sink's worker once it reads from the source it was handed
+pub struct Rebound(pub Receiver<()>);
+
+impl Rebound {
+    // Meant to be called without holding the sink table, the worker only
+    // gets to it between events
+    pub fn wait(self) -> Result<()> {
+        // the worker may have lost its old source before reading this one
+        self.0.recv().map_err(|_| anyhow!("The sink lost its source and is going away"))
+    }
+}
 
 pub trait Sink: Send + Sync {
     fn name(&self) -> &str;
+    #[allow(clippy::new_ret_no_self)]
     fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> where Self: Sized;
     fn source_name(&self) -> String;
+    // IDs of the bound sources, joined with '+' if there are several
+    fn source_id(&self) -> String;
     fn source_caps(&self) -> SourceCaps;
+    fn source_devices(&self) -> Vec<SourceIdentity>;
+    // true exactly once after the source stopped delivering events
+    fn take_source_lost(&self) -> bool;
+    // false if the sink's worker died for any reason but losing its source
+    fn is_healthy(&self) -> bool;
+    fn stats(&self) -> StatsSnapshot;
+
+    // Per-sink settings, saved and restored together with the binding
+    fn settings(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+    fn set_setting(&mut self, key: &str, _value: &str) -> Result<()> {
+        bail!("{} has no setting {}", self.name(), key)
+    }
+
+    // Feeds the sink from another source, keeping its virtual device. The
+    // source_* methods tell about the new source once it was taken.
+    fn rebind(&mut self, _source: OpenedEventSource) -> Result<Rebound> {
+        bail!("{} can't change its source", self.name())
+    }
 }
 
-pub fn list_names() -> Vec<(String, fn(OpenedEventSource) -> Result<Box<dyn Sink>>)> {
+// A sink with nothing behind it, for tests that only need one to exist
+#[cfg(test)]
+pub struct TestSink {
+    id: String,
+    name: String,
+    caps: SourceCaps,
+    devices: Vec<SourceIdentity>,
+    settings: BTreeMap<String, String>,
+}
+
+#[cfg(test)]
+impl Sink for TestSink {
+    fn name(&self) -> &str {
+        "Test"
+    }
+    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        Ok(Box::new(TestSink {
+            id: source.id.clone(),
+            name: source.name.clone(),
+            caps: source.caps,
+            devices: source.devices.clone(),
+            settings: BTreeMap::new(),
+        }))
+    }
+    fn source_name(&self) -> String {
+        self.name.clone()
+    }
+    fn source_id(&self) -> String {
+        self.id.clone()
+    }
+    fn source_caps(&self) -> SourceCaps {
+        self.caps
+    }
+    fn source_devices(&self) -> Vec<SourceIdentity> {
+        self.devices.clone()
+    }
+    fn take_source_lost(&self) -> bool {
+        false
+    }
+    fn is_healthy(&self) -> bool {
+        true
+    }
+    fn stats(&self) -> StatsSnapshot {
+        stats::SinkStats::new(Default::default()).snapshot()
+    }
+    fn settings(&self) -> BTreeMap<String, String> {
+        self.settings.clone()
+    }
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<()> {
+        self.settings.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+    fn rebind(&mut self, source: OpenedEventSource) -> Result<Rebound> {
+        let (ack, swapped) = std::sync::mpsc::channel();
+        (self.id, self.name, self.caps, self.devices) = (source.id.clone(), source.name.clone(), source.caps, source.devices.clone());
+        let _ = ack.send(());
+        Ok(Rebound(swapped))
+    }
+}
+
+pub fn list_names() -> Vec<(String, NewSinkFn)> {
     vec![
-        ("Gamepad device".to_string(), UinputSink::new),
+        (profile::XBOX_360.name.to_string(), UinputSink::new),
+        (profile::XBOX_SERIES.name.to_string(), uinput::new_xbox_series),
+        (profile::DUALSHOCK_4.name.to_string(), uinput::new_dualshock_4),
+        (profile::DUALSENSE.name.to_string(), uinput::new_dualsense),
+        (profile::SWITCH_PRO.name.to_string(), uinput::new_switch_pro),
+        ("Keyboard and mouse".to_string(), KbmSink::new),
+        ("Network".to_string(), NetSink::new),
     ]
 }
+
+pub fn find_by_name(name: &str) -> Option<NewSinkFn> {
+    list_names().into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, new_fn)| new_fn)
+}
+
+// Tells whoever holds the sink's source which player they are
+fn show_player(sink: &dyn Sink, player: u8) {
+    for node in sink.source_id().split('+') {
+        if let Err(e) = leds::show_player(Path::new(leds::SYSFS), node, player) {
+            warn!("Can't show player {} on {}: {}", player, node, e);
+        }
+    }
+}
+
+// Sinks in use, keyed by IDs that are never handed out twice
+#[derive(Default)]
+pub struct SinkTable {
+    next_id: SinkId,
+    sinks: BTreeMap<SinkId, Box<dyn Sink>>,
+    // player number of every sink, the lowest free one from 1 up
+    players: BTreeMap<SinkId, u8>,
+    // set by shutdown, nothing gets in after the last sink was taken out
+    closed: bool,
+}
+
+impl SinkTable {
+    pub fn insert(&mut self, sink: Box<dyn Sink>) -> Result<SinkId> {
+        if self.closed {
+            bail!("Shutting down, no new sinks");
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let player = (1..=u8::MAX)
+            .find(|p| !self.players.values().any(|v| v == p))
+            .unwrap_or(u8::MAX);
+        show_player(sink.as_ref(), player);
+        self.players.insert(id, player);
+        self.sinks.insert(id, sink);
+        Ok(id)
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn player(&self, id: SinkId) -> Option<u8> {
+        self.players.get(&id).copied()
+    }
+
+    pub fn get_mut(&mut self, id: SinkId) -> Option<&mut Box<dyn Sink>> {
+        self.sinks.get_mut(&id)
+    }
+
+    // None if there's no such sink. Call rebound() once the sink took the
+    // source.
+    pub fn rebind(&mut self, id: SinkId, source: OpenedEventSource) -> Option<Result<Rebound>> {
+        Some(self.sinks.get_mut(&id)?.rebind(source))
+    }
+
+    // Shows the player number of a sink on the source it switched to
+    pub fn rebound(&self, id: SinkId) {
+        if let (Some(sink), Some(player)) = (self.sinks.get(&id), self.players.get(&id)) {
+            show_player(sink.as_ref(), *player);
+        }
+    }
+
+    pub fn remove(&mut self, id: SinkId) -> Option<Box<dyn Sink>> {
+        self.players.remove(&id);
+        self.sinks.remove(&id)
+    }
+
+    // Empties the table, oldest sink first
+    pub fn take_all(&mut self) -> Vec<(SinkId, Box<dyn Sink>)> {
+        self.players.clear();
+        std::mem::take(&mut self.sinks).into_iter().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SinkId, &dyn Sink)> {
+        self.sinks.iter().map(|(id, sink)| (*id, sink.as_ref()))
+    }
+}
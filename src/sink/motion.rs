@@ -0,0 +1,233 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+};
+use evdev::{
+    AbsoluteAxisType,
+    Device,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    InputId,
+    MiscType,
+    PropType,
+    Synchronization,
+};
+use nix::{
+    libc::{c_char, input_absinfo, input_id, uinput_abs_setup, uinput_setup, UINPUT_MAX_NAME_SIZE},
+    poll::{poll, PollFd, PollFlags},
+};
+use anyhow::Result;
+use log::{debug, warn};
+
+use crate::{
+    sink::{frame::write_frame, profile::MotionProfile},
+    source::{remapped, AxisRange, StopFlag, POLL_INTERVAL},
+};
+
+const UINPUT_IOCTL_BASE: u8 = b'U';
+nix::ioctl_none!(ui_dev_create, UINPUT_IOCTL_BASE, 1);
+nix::ioctl_write_ptr!(ui_dev_setup, UINPUT_IOCTL_BASE, 3, uinput_setup);
+nix::ioctl_write_ptr!(ui_abs_setup, UINPUT_IOCTL_BASE, 4, uinput_abs_setup);
+nix::ioctl_write_int!(ui_set_evbit, UINPUT_IOCTL_BASE, 100);
+nix::ioctl_write_int!(ui_set_mscbit, UINPUT_IOCTL_BASE, 104);
+nix::ioctl_write_int!(ui_set_propbit, UINPUT_IOCTL_BASE, 110);
+
+const ACCEL: [AbsoluteAxisType; 3] = [AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y, AbsoluteAxisType::ABS_Z];
+const GYRO: [AbsoluteAxisType; 3] = [AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY, AbsoluteAxisType::ABS_RZ];
+
+fn profile_axes(profile: &MotionProfile) -> impl Iterator<Item = (AbsoluteAxisType, AxisRange, i32)> + '_ {
+    ACCEL.into_iter().map(|a| (a, profile.accel, profile.accel_res))
+        .chain(GYRO.into_iter().map(|a| (a, profile.gyro, profile.gyro_res)))
+}
+
+// The companion device carrying a pad's accelerometer and gyro. Software
+// only treats it as motion sensors if INPUT_PROP_ACCELEROMETER is set,
+// which VirtualDeviceBuilder can't do, so this talks to uinput itself.
+pub struct MotionDevice {
+    file: File,
+}
+
+impl MotionDevice {
+    pub fn new(name: &str, id: InputId, profile: &MotionProfile) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open("/dev/uinput")?;
+        let fd = file.as_raw_fd();
+
+        let mut setup = uinput_setup {
+            id: input_id {
+                bustype: id.bus_type().0,
+                vendor: id.vendor(),
+                product: id.product(),
+                version: id.version(),
+            },
+            name: [0; UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        for (dst, src) in setup.name.iter_mut().zip(name.bytes().take(UINPUT_MAX_NAME_SIZE - 1)) {
+            *dst = src as c_char;
+        }
+
+        // SAFETY: fd is an open uinput device and the structs outlive the calls
+        unsafe {
+            ui_set_evbit(fd, EventType::ABSOLUTE.0 as _)?;
+            ui_set_evbit(fd, EventType::MISC.0 as _)?;
+            ui_set_mscbit(fd, MiscType::MSC_TIMESTAMP.0 as _)?;
+            ui_set_propbit(fd, PropType::ACCELEROMETER.0 as _)?;
+            for (axis, range, resolution) in profile_axes(profile) {
+                let abs = uinput_abs_setup {
+                    code: axis.0,
+                    absinfo: input_absinfo {
+                        value: 0,
+                        minimum: range.min,
+                        maximum: range.max,
+                        fuzz: range.fuzz,
+                        flat: range.flat,
+                        resolution,
+                    },
+                };
+                ui_abs_setup(fd, &abs)?;
+            }
+            ui_dev_setup(fd, &setup)?;
+            ui_dev_create(fd)?;
+        }
+        // closing the file destroys the device
+        Ok(Self { file })
+    }
+
+    fn emit(&self, events: &[InputEvent]) -> io::Result<u64> {
+        write_frame(&self.file, events)
+    }
+}
+
+// How readings of one axis of the real sensors map onto the virtual one
+struct Scale {
+    // units per g or per degree per second, 0 if the driver didn't say
+    from_res: i32,
+    from_max: i32,
+    to: AxisRange,
+    to_res: i32,
+}
+
+impl Scale {
+    fn apply(&self, value: i32) -> i32 {
+        let value = value as i64;
+        // without a resolution the best guess is that both ranges cover the
+        // same physical span
+        let out = if self.from_res > 0 {
+            value * self.to_res as i64 / self.from_res as i64
+        } else if self.from_max > 0 {
+            value * self.to.max as i64 / self.from_max as i64
+        } else {
+            value
+        };
+        out.clamp(self.to.min as i64, self.to.max as i64) as i32
+    }
+}
+
+fn scales(source: &[input_absinfo], profile: &MotionProfile) -> Vec<(AbsoluteAxisType, Scale)> {
+    profile_axes(profile)
+        .filter_map(|(axis, to, to_res)| {
+            let from = source.get(axis.0 as usize)?;
+            Some((axis, Scale { from_res: from.resolution, from_max: from.maximum, to, to_res }))
+        })
+        .collect()
+}
+
+// Forwards the sensors at `node` to `dst` one frame at a time, until
+// stopped or the sensors go away. Hands `dst` back for the next source.
+pub fn motion_worker(node: PathBuf, dst: MotionDevice, profile: &'static MotionProfile, stop: StopFlag) -> MotionDevice {
+    let mut src = match Device::open(&node) {
+        Ok(dev) => dev,
+        Err(e) => {
+            warn!("Can't open {} for motion: {}", node.display(), e);
+            return dst;
+        },
+    };
+    // whatever reads the real sensors would get every movement twice
+    if let Err(e) = src.grab() {
+        debug!("Reading {} without grabbing it: {}", node.display(), e);
+    }
+    let scales = match src.get_abs_state() {
+        Ok(state) => scales(&state, profile),
+        Err(e) => {
+            warn!("Can't read the axes of {}: {}", node.display(), e);
+            return dst;
+        },
+    };
+
+    let mut fds = [PollFd::new(src.as_raw_fd(), PollFlags::POLLIN)];
+    let mut frame = Vec::new();
+    while !stop.is_stopped() {
+        match poll(&mut fds, POLL_INTERVAL.as_millis() as i32) {
+            Ok(0) => continue,
+            Ok(_) => (),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => return dst,
+        }
+
+        let events: Vec<InputEvent> = match src.fetch_events() {
+            Ok(events) => events.collect(),
+            Err(e) => {
+                debug!("Stopped reading {}: {}", node.display(), e);
+                return dst;
+            },
+        };
+        for ev in events {
+            match ev.kind() {
+                InputEventKind::AbsAxis(axis) => {
+                    if let Some((_, scale)) = scales.iter().find(|(a, _)| *a == axis) {
+                        let new = InputEvent::new(EventType::ABSOLUTE, axis.0, scale.apply(ev.value()));
+                        frame.push(remapped(new, &ev));
+                    }
+                },
+                InputEventKind::Misc(MiscType::MSC_TIMESTAMP) => frame.push(ev),
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) if !frame.is_empty() => {
+                    if dst.emit(&frame).is_err() {
+                        return dst;
+                    }
+                    frame.clear();
+                },
+                _ => (),
+            }
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::profile::{DUALSHOCK_4, SWITCH_PRO};
+
+    fn absinfo(maximum: i32, resolution: i32) -> input_absinfo {
+        input_absinfo { value: 0, minimum: -maximum, maximum, fuzz: 0, flat: 0, resolution }
+    }
+
+    #[test]
+    fn converts_between_driver_units() {
+        // 1g and 90 deg/s on a Switch Pro Controller
+        let mut source = [absinfo(0, 0); 64];
+        for axis in ACCEL {
+            source[axis.0 as usize] = absinfo(32767, 4096);
+        }
+        for axis in GYRO {
+            source[axis.0 as usize] = absinfo(32767000, 14247);
+        }
+        let scales = scales(&source, DUALSHOCK_4.motion);
+        assert_eq!(scales.len(), 6);
+        assert_eq!(scales[0].1.apply(4096), 8192);
+        assert_eq!(scales[3].1.apply(90 * 14247), 90 * 1024);
+        // past the range of the DualShock 4
+        assert_eq!(scales[0].1.apply(-32767), -32768);
+    }
+
+    #[test]
+    fn falls_back_to_ranges_without_resolution() {
+        let profile = SWITCH_PRO.motion;
+        let scale = Scale { from_res: 0, from_max: 1000, to: profile.accel, to_res: profile.accel_res };
+        assert_eq!(scale.apply(500), profile.accel.max / 2);
+        assert_eq!(scale.apply(-2000), profile.accel.min);
+    }
+}
@@ -0,0 +1,813 @@
+use crate::{
+    monitor::{self, Stage},
+    sink::{
+        axes,
+        frame::{self, Frame},
+        stats::{SinkStats, StatsSnapshot},
+        Sink,
+    },
+    source::{AxisRange, OpenedEventSource, SourceCaps, SourceIdentity, StopFlag, POLL_INTERVAL},
+};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use evdev::{
+    AbsoluteAxisType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    Synchronization,
+};
+use hmac_sha256::HMAC;
+use nix::sys::socket::{recv, MsgFlags};
+use anyhow::{anyhow, bail, Result};
+use log::{info, trace, warn};
+
+// Full state goes out this often even if nothing got lost, receivers take
+// it as a sign of life
+pub static STATE_INTERVAL: Duration = Duration::from_secs(1);
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Packet layout, all numbers big endian:
+//   "RIN4", version u8, flags u8, kind u8, session u32, seq u64, payload,
+//   HMAC-SHA256 of everything before it if flags has SIGNED
+// Over TCP every packet is preceded by its length as u16, both ways.
+const MAGIC: &[u8; 4] = b"RIN4";
+const VERSION: u8 = 1;
+const SIGNED: u8 = 1;
+const HEADER_LEN: usize = 19;
+const MAC_LEN: usize = 32;
+const RESYNC_LEN: usize = HEADER_LEN + 8 + MAC_LEN;
+
+const KIND_FRAME: u8 = 1;
+const KIND_STATE: u8 = 2;
+const KIND_RESYNC: u8 = 3;
+
+const CAPS_FULL_X360: u8 = 0;
+const CAPS_DPAD_AND_AB: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    // picked at random by the sender, a new one means it restarted
+    pub session: u32,
+    // one up for every packet of a session
+    pub seq: u64,
+}
+
+pub enum Packet {
+    // the events of one frame, without the SYN_REPORT
+    Frame(Vec<InputEvent>),
+    // everything a receiver needs to set up the pad and bring it up to
+    // date: the last value of every key and axis the source ever sent
+    State {
+        // the receiver's latest nonce, 0 before it sent one. Only a state
+        // carrying the current one may start a session, so a recorded state
+        // can't bring back a session that ended.
+        nonce: u64,
+        name: String,
+        caps: SourceCaps,
+        axes: Vec<(AbsoluteAxisType, AxisRange)>,
+        events: Vec<InputEvent>,
+    },
+    // sent back by a receiver that missed something or doesn't know the
+    // session yet, along with the nonce the next state has to carry
+    Resync { nonce: u64 },
+}
+
+fn put_events(out: &mut Vec<u8>, events: &[InputEvent]) {
+    out.extend_from_slice(&(events.len() as u16).to_be_bytes());
+    for ev in events {
+        out.extend_from_slice(&ev.event_type().0.to_be_bytes());
+        out.extend_from_slice(&ev.code().to_be_bytes());
+        out.extend_from_slice(&ev.value().to_be_bytes());
+    }
+}
+
+pub fn encode(header: Header, packet: &Packet, psk: Option<&[u8]>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.push(if psk.is_some() { SIGNED } else { 0 });
+    out.push(match packet {
+        Packet::Frame(_) => KIND_FRAME,
+        Packet::State { .. } => KIND_STATE,
+        Packet::Resync { .. } => KIND_RESYNC,
+    });
+    out.extend_from_slice(&header.session.to_be_bytes());
+    out.extend_from_slice(&header.seq.to_be_bytes());
+
+    match packet {
+        Packet::Frame(events) => put_events(&mut out, events),
+        Packet::State { nonce, name, caps, axes, events } => {
+            out.extend_from_slice(&nonce.to_be_bytes());
+            // cut at a char boundary so the receiver still gets valid UTF-8
+            let mut len = name.len().min(u8::MAX as usize);
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            out.push(len as u8);
+            out.extend_from_slice(&name.as_bytes()[..len]);
+            out.push(match caps {
+                SourceCaps::FullX360 => CAPS_FULL_X360,
+                SourceCaps::DpadAndAB => CAPS_DPAD_AND_AB,
+            });
+            out.push(axes.len() as u8);
+            for (axis, range) in axes {
+                out.extend_from_slice(&axis.0.to_be_bytes());
+                for v in [range.min, range.max, range.fuzz, range.flat] {
+                    out.extend_from_slice(&v.to_be_bytes());
+                }
+            }
+            put_events(&mut out, events);
+        },
+        Packet::Resync { nonce } => out.extend_from_slice(&nonce.to_be_bytes()),
+    }
+
+    if let Some(key) = psk {
+        let mac = HMAC::mac(&out, key);
+        out.extend_from_slice(&mac);
+    }
+    out
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("Truncated packet");
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+    fn events(&mut self) -> Result<Vec<InputEvent>> {
+        let count = self.u16()?;
+        let mut ret = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (type_, code, value) = (self.u16()?, self.u16()?, self.i32()?);
+            // receivers feed these to uinput, nothing but pad input may pass
+            if type_ != EventType::KEY.0 && type_ != EventType::ABSOLUTE.0 {
+                bail!("Unexpected event type {}", type_);
+            }
+            ret.push(InputEvent::new_now(EventType(type_), code, value));
+        }
+        Ok(ret)
+    }
+}
+
+// Fails on anything not sent by a sender using the same key, or lack of one
+pub fn decode(data: &[u8], psk: Option<&[u8]>) -> Result<(Header, Packet)> {
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        bail!("Not a rinputer4 packet");
+    }
+    if data[4] != VERSION {
+        bail!("Unsupported protocol version {}", data[4]);
+    }
+    let body = match (data[5] & SIGNED != 0, psk) {
+        (true, Some(key)) => {
+            if data.len() < HEADER_LEN + MAC_LEN {
+                bail!("Truncated packet");
+            }
+            let (body, mac) = data.split_at(data.len() - MAC_LEN);
+            if !HMAC::verify(body, key, mac.try_into()?) {
+                bail!("Packet signed with another key");
+            }
+            body
+        },
+        (false, None) => data,
+        (true, None) => bail!("Packet is signed but no key is set"),
+        (false, Some(_)) => bail!("Packet is not signed"),
+    };
+
+    let mut r = Reader(&body[6..]);
+    let kind = r.u8()?;
+    let header = Header { session: r.u32()?, seq: r.u64()? };
+    let packet = match kind {
+        KIND_FRAME => Packet::Frame(r.events()?),
+        KIND_STATE => {
+            let nonce = r.u64()?;
+            let len = r.u8()? as usize;
+            let name = String::from_utf8(r.take(len)?.to_vec())?;
+            let caps = match r.u8()? {
+                CAPS_FULL_X360 => SourceCaps::FullX360,
+                CAPS_DPAD_AND_AB => SourceCaps::DpadAndAB,
+                other => bail!("Unknown source caps {}", other),
+            };
+            let mut axes = Vec::new();
+            for _ in 0..r.u8()? {
+                let axis = AbsoluteAxisType(r.u16()?);
+                axes.push((axis, AxisRange { min: r.i32()?, max: r.i32()?, fuzz: r.i32()?, flat: r.i32()? }));
+            }
+            Packet::State { nonce, name, caps, axes, events: r.events()? }
+        },
+        KIND_RESYNC => Packet::Resync { nonce: r.u64()? },
+        _ => bail!("Unknown packet kind {}", kind),
+    };
+    if !r.0.is_empty() {
+        bail!("Trailing data after packet");
+    }
+    Ok((header, packet))
+}
+
+// What a receiver should do with a packet
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Apply,
+    // apply it, but something before it got lost
+    ApplyAndResync,
+    // useless without the full state of the session
+    Resync,
+    // older than what was applied already
+    Drop,
+}
+
+// Tracks the packets of one sender on the receiving side
+#[derive(Default)]
+pub struct Sequence {
+    session: Option<u32>,
+    last: u64,
+}
+
+impl Sequence {
+    pub fn check(&mut self, header: Header, is_state: bool) -> Verdict {
+        if self.session != Some(header.session) {
+            // a frame means nothing without the state it builds on
+            if !is_state {
+                return Verdict::Resync;
+            }
+            self.session = Some(header.session);
+            self.last = header.seq;
+            return Verdict::Apply;
+        }
+        if header.seq <= self.last {
+            return Verdict::Drop;
+        }
+        // a lost frame might have released a key, only a full state tells
+        let gap = header.seq > self.last + 1;
+        self.last = header.seq;
+        if gap && !is_state {
+            Verdict::ApplyAndResync
+        } else {
+            Verdict::Apply
+        }
+    }
+
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+}
+
+// Straight from the kernel, RandomState keys only get seeded from it once
+// per thread
+fn random() -> u64 {
+    let mut buf = [0u8; 8];
+    match fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+        Ok(()) => u64::from_ne_bytes(buf),
+        Err(e) => {
+            warn!("Can't read /dev/urandom: {}", e);
+            RandomState::new().build_hasher().finish()
+        },
+    }
+}
+
+// Unpredictable and never 0, which stands for no nonce at all
+pub fn new_nonce() -> u64 {
+    loop {
+        let nonce = random();
+        if nonce != 0 {
+            return nonce;
+        }
+    }
+}
+
+// Reads a pre-shared key, surrounding whitespace doesn't count
+pub fn read_psk(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path).map_err(|e| anyhow!("Can't read {}: {}", path.display(), e))?;
+    let key = data.trim_ascii();
+    if key.is_empty() {
+        bail!("{} holds no key", path.display());
+    }
+    Ok(key.to_vec())
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+struct Settings {
+    // host:port of the receiving daemon, empty while sending nowhere
+    peer: String,
+    transport: Transport,
+    psk_file: String,
+    psk: Option<Vec<u8>>,
+    // bumped on every change, the worker then starts over
+    generation: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self { peer: String::new(), transport: Transport::Udp, psk_file: String::new(), psk: None, generation: 0 }
+    }
+}
+
+impl Settings {
+    fn to_map(&self) -> BTreeMap<String, String> {
+        let mut ret = BTreeMap::new();
+        ret.insert("peer".to_string(), self.peer.clone());
+        ret.insert("transport".to_string(), if self.transport == Transport::Udp { "udp" } else { "tcp" }.to_string());
+        ret.insert("psk_file".to_string(), self.psk_file.clone());
+        ret
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "peer" => {
+                let valid = value.rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+                if !value.is_empty() && !valid {
+                    bail!("peer is host:port of the receiving machine, not {}", value);
+                }
+                self.peer = value.to_string();
+            },
+            "transport" => match value {
+                "udp" => self.transport = Transport::Udp,
+                "tcp" => self.transport = Transport::Tcp,
+                _ => bail!("transport is udp or tcp, not {}", value),
+            },
+            "psk_file" => {
+                self.psk = if value.is_empty() { None } else { Some(read_psk(Path::new(value))?) };
+                self.psk_file = value.to_string();
+            },
+            _ => bail!("Unknown setting {}", key),
+        }
+        self.generation += 1;
+        Ok(())
+    }
+}
+
+// Receivers take nothing but keys and axes, a frame with anything else in
+// it (like the MSC_SCAN hid-generic sends with every button) gets rejected
+fn is_pad_input(ev: &InputEvent) -> bool {
+    matches!(ev.kind(), InputEventKind::Key(_) | InputEventKind::AbsAxis(_))
+}
+
+// Last value of every key and axis the source sent, which makes up the
+// whole state of the pad
+#[derive(Default)]
+struct Snapshot(BTreeMap<(u16, u16), i32>);
+
+impl Snapshot {
+    fn record(&mut self, ev: &InputEvent) {
+        if is_pad_input(ev) {
+            self.0.insert((ev.event_type().0, ev.code()), ev.value());
+        }
+    }
+
+    fn events(&self) -> Vec<InputEvent> {
+        self.0.iter()
+            .map(|((type_, code), value)| InputEvent::new(EventType(*type_), *code, *value))
+            .collect()
+    }
+
+    // Every key released and every axis at rest
+    fn neutral(&self, ranges: &[(AbsoluteAxisType, AxisRange)]) -> Vec<InputEvent> {
+        self.0.keys()
+            .map(|(type_, code)| {
+                let value = ranges.iter()
+                    .find(|(axis, _)| *type_ == EventType::ABSOLUTE.0 && axis.0 == *code)
+                    .map_or(0, |(axis, range)| axes::rest(*axis, range));
+                InputEvent::new(EventType(*type_), *code, value)
+            })
+            .collect()
+    }
+}
+
+// Tells runs of a sender apart, so receivers notice a restart
+fn new_session() -> u32 {
+    random() as u32
+}
+
+enum Link {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Link {
+    fn open(peer: &str, transport: Transport) -> Result<Self> {
+        let addr = peer.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("{} has no address", peer))?;
+        match transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                socket.connect(addr)?;
+                // only polled for resync requests
+                socket.set_nonblocking(true)?;
+                Ok(Link::Udp(socket))
+            },
+            Transport::Tcp => {
+                let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+                stream.set_nodelay(true)?;
+                stream.set_write_timeout(Some(STATE_INTERVAL))?;
+                Ok(Link::Tcp(stream))
+            },
+        }
+    }
+
+    // Returns how many writes the packet took
+    fn send(&mut self, data: &[u8]) -> io::Result<u64> {
+        match self {
+            // a receiver that isn't up yet makes sends fail, that's just
+            // another lost packet
+            Link::Udp(socket) => {
+                let _ = socket.send(data);
+                Ok(1)
+            },
+            Link::Tcp(stream) => {
+                let mut buf = (data.len() as u16).to_be_bytes().to_vec();
+                buf.extend_from_slice(data);
+                frame::write_counted(&buf, |buf| stream.write(buf))
+            },
+        }
+    }
+
+    // Returns the nonce of the last request for full state, without waiting
+    // for one
+    fn poll_resync(&mut self, session: u32, psk: Option<&[u8]>) -> io::Result<Option<u64>> {
+        let mut ret = None;
+        let mut check = |data: &[u8]| {
+            if let Ok((header, Packet::Resync { nonce })) = decode(data, psk) {
+                if header.session == session {
+                    ret = Some(nonce);
+                }
+            }
+        };
+        let mut buf = [0u8; 2 + RESYNC_LEN];
+        match self {
+            Link::Udp(socket) => {
+                while let Ok(n) = socket.recv(&mut buf) {
+                    check(&buf[..n]);
+                }
+            },
+            // only whole packets are taken off the stream, the rest stays
+            // there until it all arrived
+            Link::Tcp(stream) => loop {
+                let n = match recv(stream.as_raw_fd(), &mut buf, MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => n,
+                    Err(nix::errno::Errno::EAGAIN) => break,
+                    Err(e) => return Err(e.into()),
+                };
+                if n < 2 {
+                    break;
+                }
+                let len = 2 + u16::from_be_bytes([buf[0], buf[1]]) as usize;
+                if len > buf.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Receiver sent an oversized packet"));
+                }
+                if n < len {
+                    break;
+                }
+                stream.read_exact(&mut buf[..len])?;
+                check(&buf[2..len]);
+            },
+        }
+        Ok(ret)
+    }
+}
+
+struct Sender {
+    session: u32,
+    seq: u64,
+    link: Option<Link>,
+    psk: Option<Vec<u8>>,
+    nonce: u64,
+}
+
+impl Sender {
+    // Whether the receiver asked for full state, dropping the link once it
+    // broke
+    fn resync_requested(&mut self) -> bool {
+        let link = match self.link.as_mut() {
+            Some(l) => l,
+            None => return false,
+        };
+        match link.poll_resync(self.session, self.psk.as_deref()) {
+            Ok(Some(nonce)) => {
+                self.nonce = nonce;
+                true
+            },
+            Ok(None) => false,
+            Err(e) => {
+                warn!("Lost connection to the receiver: {}", e);
+                self.link = None;
+                false
+            },
+        }
+    }
+
+
+    // Sends a packet if there's a link, dropping it once it broke.
+    // Returns how many writes that took.
+    fn send(&mut self, packet: &Packet) -> Option<u64> {
+        self.seq += 1;
+        let link = self.link.as_mut()?;
+        let data = encode(Header { session: self.session, seq: self.seq }, packet, self.psk.as_deref());
+        match link.send(&data) {
+            Ok(writes) => Some(writes),
+            Err(e) => {
+                warn!("Lost connection to the receiver: {}", e);
+                self.link = None;
+                None
+            },
+        }
+    }
+}
+
+fn net_worker(src: OpenedEventSource, settings: Arc<Mutex<Settings>>, stop: StopFlag, lost: Arc<AtomicBool>, stats: Arc<SinkStats>) {
+    let mut out = Sender { session: new_session(), seq: 0, link: None, psk: None, nonce: 0 };
+    let mut snapshot = Snapshot::default();
+    let mut frame = Frame::default();
+    let mut generation = None;
+    let mut target = None;
+    let mut failing = false;
+    let mut next_state = Instant::now();
+
+    while !stop.is_stopped() {
+        {
+            let settings = settings.lock().unwrap();
+            if generation != Some(settings.generation) {
+                generation = Some(settings.generation);
+                target = (!settings.peer.is_empty()).then(|| (settings.peer.clone(), settings.transport));
+                out.psk = settings.psk.clone();
+                out.link = None;
+                out.nonce = 0;
+                failing = false;
+                next_state = Instant::now();
+            }
+        }
+        if out.resync_requested() {
+            next_state = Instant::now();
+        }
+
+        // full state also brings a new connection up to date
+        if Instant::now() >= next_state {
+            next_state = Instant::now() + STATE_INTERVAL;
+            if let (None, Some((peer, transport))) = (&out.link, &target) {
+                match Link::open(peer, *transport) {
+                    Ok(link) => {
+                        info!("Sending {} to {}", src.id, peer);
+                        out.link = Some(link);
+                        failing = false;
+                    },
+                    Err(e) if !failing => {
+                        warn!("Can't send {} to {}: {}", src.id, peer, e);
+                        failing = true;
+                    },
+                    Err(_) => (),
+                }
+            }
+            out.send(&Packet::State {
+                nonce: out.nonce,
+                name: src.name.clone(),
+                caps: src.caps,
+                axes: src.axes.clone(),
+                events: snapshot.events(),
+            });
+        }
+
+        let timeout = next_state.saturating_duration_since(Instant::now()).min(POLL_INTERVAL);
+        let ev = match src.chan.recv_timeout(timeout) {
+            Ok(ev) => ev,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if ev.kind() != InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
+            if is_pad_input(&ev) {
+                frame.push(ev);
+            }
+            continue;
+        }
+        if frame.is_empty() {
+            continue;
+        }
+
+        let events = frame.take();
+        for ev in &events {
+            snapshot.record(ev);
+        }
+        let writes = match out.send(&Packet::Frame(events.clone())) {
+            Some(w) => w,
+            None => continue,
+        };
+        stats.record_frame(writes);
+        for ev in &events {
+            stats.record(ev);
+            trace!("{} -> {:?}", src.id, ev);
+            monitor::publish(Stage::Output, &src.id, ev);
+        }
+    }
+
+    if !stop.is_stopped() {
+        info!("Source {} of a sink went away", src.id);
+        lost.store(true, Ordering::Relaxed);
+    }
+
+    // the receiver would only let go once it stops hearing from us
+    out.send(&Packet::Frame(snapshot.neutral(&src.axes)));
+    drop(src);
+}
+
+pub struct NetSink {
+    source_id: String,
+    source_name: String,
+    source_caps: SourceCaps,
+    source_devices: Vec<SourceIdentity>,
+    source_lost: Arc<AtomicBool>,
+    lost_reported: AtomicBool,
+    stop: StopFlag,
+    stats: Arc<SinkStats>,
+    settings: Arc<Mutex<Settings>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for NetSink {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Sink for NetSink {
+    fn name(&self) -> &'static str {
+        "Network"
+    }
+    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        let stop = StopFlag::default();
+        let stop2 = stop.clone();
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost2 = Arc::clone(&lost);
+        let stats = Arc::new(SinkStats::new(source.chan.depth()));
+        let stats2 = Arc::clone(&stats);
+        let settings = Arc::new(Mutex::new(Settings::default()));
+        let settings2 = Arc::clone(&settings);
+
+        let mut out = Box::new(NetSink {
+            source_id: source.id.clone(),
+            source_name: source.name.clone(),
+            source_caps: source.caps,
+            source_devices: source.devices.clone(),
+            source_lost: lost,
+            lost_reported: AtomicBool::new(false),
+            stop,
+            stats,
+            settings,
+            worker: None,
+        });
+
+        out.worker = Some(std::thread::spawn(move || net_worker(source, settings2, stop2, lost2, stats2)));
+        Ok(out)
+    }
+    fn source_name(&self) -> String {
+        self.source_name.clone()
+    }
+    fn source_id(&self) -> String {
+        self.source_id.clone()
+    }
+    fn source_caps(&self) -> SourceCaps {
+        self.source_caps
+    }
+    fn source_devices(&self) -> Vec<SourceIdentity> {
+        self.source_devices.clone()
+    }
+    fn take_source_lost(&self) -> bool {
+        self.source_lost.load(Ordering::Relaxed) && !self.lost_reported.swap(true, Ordering::Relaxed)
+    }
+    fn is_healthy(&self) -> bool {
+        let finished = self.worker.as_ref().is_none_or(|w| w.is_finished());
+        !finished || self.source_lost.load(Ordering::Relaxed)
+    }
+    fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+    fn settings(&self) -> BTreeMap<String, String> {
+        self.settings.lock().unwrap().to_map()
+    }
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<()> {
+        self.settings.lock().unwrap().set(key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::Key;
+
+    fn kinds(events: &[InputEvent]) -> Vec<(InputEventKind, i32)> {
+        events.iter().map(|e| (e.kind(), e.value())).collect()
+    }
+
+    #[test]
+    fn packets_survive_the_round_trip_and_need_the_key() {
+        let header = Header { session: 7, seq: 42 };
+        let stick = AxisRange { min: -32768, max: 32767, fuzz: 16, flat: 128 };
+        let state = Packet::State {
+            nonce: 1234,
+            name: "Pad".to_string(),
+            caps: SourceCaps::DpadAndAB,
+            axes: vec![(AbsoluteAxisType::ABS_X, stick)],
+            events: vec![
+                InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1),
+                InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, -300),
+            ],
+        };
+
+        let data = encode(header, &state, Some(b"secret"));
+        match decode(&data, Some(b"secret")).unwrap() {
+            (h, Packet::State { nonce, name, caps, axes, events }) => {
+                assert_eq!(h, header);
+                assert_eq!(nonce, 1234);
+                assert_eq!(name, "Pad");
+                assert_eq!(caps, SourceCaps::DpadAndAB);
+                assert_eq!(axes, [(AbsoluteAxisType::ABS_X, stick)]);
+                assert_eq!(kinds(&events), [
+                    (InputEventKind::Key(Key::BTN_SOUTH), 1),
+                    (InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X), -300),
+                ]);
+            },
+            _ => panic!("not a state"),
+        }
+        assert!(decode(&data, Some(b"guess")).is_err());
+        assert!(decode(&data, None).is_err());
+        assert!(decode(&data[..data.len() - 1], Some(b"secret")).is_err());
+
+        let mut tampered = encode(header, &Packet::Frame(Vec::new()), Some(b"secret"));
+        tampered[HEADER_LEN - 1] ^= 1;
+        assert!(decode(&tampered, Some(b"secret")).is_err());
+        let unsigned = encode(header, &Packet::Frame(Vec::new()), None);
+        assert!(decode(&unsigned, Some(b"secret")).is_err());
+        assert!(matches!(decode(&unsigned, None), Ok((_, Packet::Frame(e))) if e.is_empty()));
+
+        let resync = encode(header, &Packet::Resync { nonce: u64::MAX }, Some(b"secret"));
+        assert_eq!(resync.len(), RESYNC_LEN);
+        assert!(matches!(decode(&resync, Some(b"secret")), Ok((_, Packet::Resync { nonce: u64::MAX }))));
+    }
+
+    #[test]
+    fn sequence_asks_for_state_after_loss() {
+        let mut seq = Sequence::default();
+        let at = |session, seq| Header { session, seq };
+
+        assert_eq!(seq.check(at(1, 5), false), Verdict::Resync);
+        assert_eq!(seq.check(at(1, 6), true), Verdict::Apply);
+        assert_eq!(seq.check(at(1, 7), false), Verdict::Apply);
+        assert_eq!(seq.check(at(1, 7), false), Verdict::Drop);
+        assert_eq!(seq.check(at(1, 9), false), Verdict::ApplyAndResync);
+        assert_eq!(seq.check(at(1, 8), false), Verdict::Drop);
+        assert_eq!(seq.check(at(1, 12), true), Verdict::Apply);
+        // the sender restarted
+        assert_eq!(seq.check(at(2, 1), false), Verdict::Resync);
+        assert_eq!(seq.check(at(2, 2), true), Verdict::Apply);
+        assert_eq!(seq.session(), Some(2));
+    }
+
+    #[test]
+    fn nonces_differ() {
+        let nonces: std::collections::HashSet<u64> = (0..100).map(|_| new_nonce()).collect();
+        assert_eq!(nonces.len(), 100);
+        assert!(!nonces.contains(&0));
+    }
+}
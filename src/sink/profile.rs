@@ -0,0 +1,192 @@
+use evdev::{
+    AbsoluteAxisType,
+    BusType,
+    InputId,
+    Key,
+};
+
+use crate::source::AxisRange;
+
+// What kind of controller a UinputSink pretends to be. IDs and ranges match
+// what the kernel drivers of the real pads report, games look at both.
+pub struct Profile {
+    pub name: &'static str,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    pub keys: &'static [Key],
+    pub axes: &'static [(AbsoluteAxisType, AxisRange)],
+    pub motion: &'static MotionProfile,
+}
+
+// The motion sensor device the pad's driver would create next to it.
+// Resolutions are units per g and units per degree per second.
+pub struct MotionProfile {
+    pub suffix: &'static str,
+    pub accel: AxisRange,
+    pub accel_res: i32,
+    pub gyro: AxisRange,
+    pub gyro_res: i32,
+}
+
+const fn range(min: i32, max: i32, fuzz: i32, flat: i32) -> AxisRange {
+    AxisRange { min, max, fuzz, flat }
+}
+
+const XBOX_STICK: AxisRange = range(-32768, 32767, 16, 128);
+const HAT: AxisRange = range(-1, 1, 0, 0);
+const PS_STICK: AxisRange = range(0, 255, 0, 0);
+const PS_TRIGGER: AxisRange = range(0, 255, 0, 0);
+const NINTENDO_STICK: AxisRange = range(-32767, 32767, 250, 500);
+
+// Xbox pads have no IMU, those get the hid-playstation one which Steam and
+// emulators know best
+static PS_MOTION: MotionProfile = MotionProfile {
+    suffix: "Motion Sensors",
+    accel: range(-32768, 32768, 16, 0),
+    accel_res: 8192,
+    gyro: range(-2048 * 1024, 2048 * 1024, 16, 0),
+    gyro_res: 1024,
+};
+
+static NINTENDO_MOTION: MotionProfile = MotionProfile {
+    suffix: "IMU",
+    accel: range(-32767, 32767, 10, 0),
+    accel_res: 4096,
+    gyro: range(-32767000, 32767000, 10, 0),
+    gyro_res: 14247,
+};
+
+const XBOX_KEYS: &[Key] = &[
+    Key::BTN_SOUTH, Key::BTN_EAST, Key::BTN_NORTH, Key::BTN_WEST,
+    Key::BTN_TL, Key::BTN_TR, Key::BTN_SELECT, Key::BTN_START, Key::BTN_MODE,
+    Key::BTN_THUMBL, Key::BTN_THUMBR,
+];
+
+// hid-playstation reports the triggers both as buttons and as axes
+const PS_KEYS: &[Key] = &[
+    Key::BTN_SOUTH, Key::BTN_EAST, Key::BTN_NORTH, Key::BTN_WEST,
+    Key::BTN_TL, Key::BTN_TR, Key::BTN_TL2, Key::BTN_TR2,
+    Key::BTN_SELECT, Key::BTN_START, Key::BTN_MODE,
+    Key::BTN_THUMBL, Key::BTN_THUMBR,
+];
+
+const PS_AXES: &[(AbsoluteAxisType, AxisRange)] = &[
+    (AbsoluteAxisType::ABS_X, PS_STICK),
+    (AbsoluteAxisType::ABS_Y, PS_STICK),
+    (AbsoluteAxisType::ABS_RX, PS_STICK),
+    (AbsoluteAxisType::ABS_RY, PS_STICK),
+    (AbsoluteAxisType::ABS_Z, PS_TRIGGER),
+    (AbsoluteAxisType::ABS_RZ, PS_TRIGGER),
+    (AbsoluteAxisType::ABS_HAT0X, HAT),
+    (AbsoluteAxisType::ABS_HAT0Y, HAT),
+];
+
+pub static XBOX_360: Profile = Profile {
+    name: "Gamepad device",
+    vendor: 0x045e,
+    product: 0x028e,
+    version: 0x2137,
+    keys: XBOX_KEYS,
+    axes: &[
+        (AbsoluteAxisType::ABS_X, XBOX_STICK),
+        (AbsoluteAxisType::ABS_Y, XBOX_STICK),
+        (AbsoluteAxisType::ABS_RX, XBOX_STICK),
+        (AbsoluteAxisType::ABS_RY, XBOX_STICK),
+        (AbsoluteAxisType::ABS_Z, range(0, 255, 0, 0)),
+        (AbsoluteAxisType::ABS_RZ, range(0, 255, 0, 0)),
+        (AbsoluteAxisType::ABS_HAT0X, HAT),
+        (AbsoluteAxisType::ABS_HAT0Y, HAT),
+    ],
+    motion: &PS_MOTION,
+};
+
+pub static XBOX_SERIES: Profile = Profile {
+    name: "Xbox One/Series controller",
+    vendor: 0x045e,
+    product: 0x0b12,
+    version: 0x0507,
+    keys: XBOX_KEYS,
+    axes: &[
+        (AbsoluteAxisType::ABS_X, XBOX_STICK),
+        (AbsoluteAxisType::ABS_Y, XBOX_STICK),
+        (AbsoluteAxisType::ABS_RX, XBOX_STICK),
+        (AbsoluteAxisType::ABS_RY, XBOX_STICK),
+        (AbsoluteAxisType::ABS_Z, range(0, 1023, 0, 0)),
+        (AbsoluteAxisType::ABS_RZ, range(0, 1023, 0, 0)),
+        (AbsoluteAxisType::ABS_HAT0X, HAT),
+        (AbsoluteAxisType::ABS_HAT0Y, HAT),
+    ],
+    motion: &PS_MOTION,
+};
+
+pub static DUALSHOCK_4: Profile = Profile {
+    name: "DualShock 4",
+    vendor: 0x054c,
+    product: 0x09cc,
+    version: 0x8111,
+    keys: PS_KEYS,
+    axes: PS_AXES,
+    motion: &PS_MOTION,
+};
+
+pub static DUALSENSE: Profile = Profile {
+    name: "DualSense",
+    vendor: 0x054c,
+    product: 0x0ce6,
+    version: 0x8111,
+    keys: PS_KEYS,
+    axes: PS_AXES,
+    motion: &PS_MOTION,
+};
+
+// the triggers are buttons only and capture is BTN_Z, like hid-nintendo does it
+pub static SWITCH_PRO: Profile = Profile {
+    name: "Switch Pro Controller",
+    vendor: 0x057e,
+    product: 0x2009,
+    version: 0x8111,
+    keys: &[
+        Key::BTN_SOUTH, Key::BTN_EAST, Key::BTN_NORTH, Key::BTN_WEST,
+        Key::BTN_TL, Key::BTN_TR, Key::BTN_TL2, Key::BTN_TR2,
+        Key::BTN_SELECT, Key::BTN_START, Key::BTN_MODE, Key::BTN_Z,
+        Key::BTN_THUMBL, Key::BTN_THUMBR,
+    ],
+    axes: &[
+        (AbsoluteAxisType::ABS_X, NINTENDO_STICK),
+        (AbsoluteAxisType::ABS_Y, NINTENDO_STICK),
+        (AbsoluteAxisType::ABS_RX, NINTENDO_STICK),
+        (AbsoluteAxisType::ABS_RY, NINTENDO_STICK),
+        (AbsoluteAxisType::ABS_HAT0X, HAT),
+        (AbsoluteAxisType::ABS_HAT0Y, HAT),
+    ],
+    motion: &NINTENDO_MOTION,
+};
+
+impl Profile {
+    pub fn input_id(&self) -> InputId {
+        InputId::new(BusType::BUS_USB, self.vendor, self.product, self.version)
+    }
+
+    pub fn axis(&self, axis: AbsoluteAxisType) -> Option<AxisRange> {
+        self.axes.iter()
+            .find(|(a, _)| *a == axis)
+            .map(|(_, range)| *range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ALL: [&Profile; 5] = [&XBOX_360, &XBOX_SERIES, &DUALSHOCK_4, &DUALSENSE, &SWITCH_PRO];
+
+    #[test]
+    fn profiles_are_distinct_and_sane() {
+        for (i, p) in ALL.iter().enumerate() {
+            assert!(ALL[i + 1..].iter().all(|q| q.name != p.name && q.product != p.product));
+            assert!(p.axes.iter().all(|(_, r)| r.min < r.max));
+            assert!(p.motion.accel_res > 0 && p.motion.gyro_res > 0);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, BTreeSet};
+use evdev::{
+    AbsoluteAxisType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    Key,
+};
+
+use crate::sink::{axes, profile::Profile};
+
+// What a virtual pad currently reports, so it can be put back at rest
+// before it goes away or gets another source
+pub struct PadState {
+    profile: &'static Profile,
+    keys: BTreeSet<Key>,
+    // by axis code, only axes that were moved
+    axes: BTreeMap<u16, i32>,
+}
+
+impl PadState {
+    pub fn new(profile: &'static Profile) -> Self {
+        Self { profile, keys: BTreeSet::new(), axes: BTreeMap::new() }
+    }
+
+    // Call with every event written to the pad
+    pub fn record(&mut self, ev: &InputEvent) {
+        match ev.kind() {
+            InputEventKind::Key(key) if ev.value() == 0 => {
+                self.keys.remove(&key);
+            },
+            InputEventKind::Key(key) => {
+                self.keys.insert(key);
+            },
+            InputEventKind::AbsAxis(axis) => {
+                self.axes.insert(axis.0, ev.value());
+            },
+            _ => (),
+        }
+    }
+
+    // Releases every held button and centers every moved axis, forgetting
+    // both. Axes the profile doesn't know rest at 0.
+    pub fn neutral(&mut self) -> Vec<InputEvent> {
+        let mut ret: Vec<InputEvent> = std::mem::take(&mut self.keys).into_iter()
+            .map(|key| InputEvent::new(EventType::KEY, key.code(), 0))
+            .collect();
+        for (code, value) in std::mem::take(&mut self.axes) {
+            let axis = AbsoluteAxisType(code);
+            let rest = self.profile.axis(axis).map_or(0, |r| axes::rest(axis, &r));
+            if value != rest {
+                ret.push(InputEvent::new(EventType::ABSOLUTE, code, rest));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::profile::{DUALSHOCK_4, XBOX_360};
+
+    fn values(events: &[InputEvent]) -> Vec<(InputEventKind, i32)> {
+        events.iter().map(|e| (e.kind(), e.value())).collect()
+    }
+
+    #[test]
+    fn releases_held_keys_only() {
+        let mut state = PadState::new(&XBOX_360);
+        state.record(&InputEvent::new(EventType::KEY, Key::BTN_SOUTH.code(), 1));
+        state.record(&InputEvent::new(EventType::KEY, Key::BTN_EAST.code(), 1));
+        state.record(&InputEvent::new(EventType::KEY, Key::BTN_EAST.code(), 0));
+        assert_eq!(values(&state.neutral()), [(InputEventKind::Key(Key::BTN_SOUTH), 0)]);
+        assert!(state.neutral().is_empty());
+    }
+
+    #[test]
+    fn centers_sticks_and_zeroes_triggers() {
+        let mut state = PadState::new(&DUALSHOCK_4);
+        state.record(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 3));
+        state.record(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, 200));
+        state.record(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0Y.0, 0));
+        assert_eq!(values(&state.neutral()), [
+            (InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X), 128),
+            (InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Z), 0),
+        ]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use evdev::InputEvent;
+use serde::Serialize;
+
+// Rates and latencies are averaged over windows of about this long
+static WINDOW: Duration = Duration::from_secs(1);
+
+struct Window {
+    start: Instant,
+    events: u64,
+    latency_sum: Duration,
+    latency_samples: u32,
+    latency_max: Duration,
+}
+
+impl Window {
+    fn new(start: Instant) -> Self {
+        Self { start, events: 0, latency_sum: Duration::ZERO, latency_samples: 0, latency_max: Duration::ZERO }
+    }
+}
+
+struct Inner {
+    forwarded: u64,
+    frames: u64,
+    writes: u64,
+    last_event: Option<Instant>,
+    current: Window,
+    // results of the last finished window
+    events_per_second: f64,
+    latency_avg: Option<Duration>,
+    latency_max: Option<Duration>,
+}
+
+impl Inner {
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now - self.current.start;
+        if elapsed < WINDOW {
+            return;
+        }
+        let done = std::mem::replace(&mut self.current, Window::new(now));
+        self.events_per_second = done.events as f64 / elapsed.as_secs_f64();
+        if done.latency_samples > 0 {
+            self.latency_avg = Some(done.latency_sum / done.latency_samples);
+            self.latency_max = Some(done.latency_max);
+        } else {
+            self.latency_avg = None;
+            self.latency_max = None;
+        }
+    }
+}
+
+// Collected by a sink's worker for every event it emits
+pub struct SinkStats {
+    inner: Mutex<Inner>,
+    queue_depth: Mutex<Arc<AtomicUsize>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatsSnapshot {
+    pub events_forwarded: u64,
+    pub events_per_second: f64,
+    pub last_event_ms_ago: Option<u64>,
+    pub queue_depth: usize,
+    // from the kernel timestamp of the source event to the uinput write
+    pub latency_avg_us: Option<u64>,
+    pub latency_max_us: Option<u64>,
+    pub frames_forwarded: u64,
+    // uinput write syscalls per frame, since the sink started
+    pub writes_per_frame: Option<f64>,
+}
+
+impl SinkStats {
+    // `queue_depth` is the counter of the source channel the sink reads
+    pub fn new(queue_depth: Arc<AtomicUsize>) -> Self {
+        let inner = Inner {
+            forwarded: 0,
+            frames: 0,
+            writes: 0,
+            last_event: None,
+            current: Window::new(Instant::now()),
+            events_per_second: 0.0,
+            latency_avg: None,
+            latency_max: None,
+        };
+        Self { inner: Mutex::new(inner), queue_depth: Mutex::new(queue_depth) }
+    }
+
+    // For when the sink starts reading another source
+    pub fn set_queue_depth(&self, queue_depth: Arc<AtomicUsize>) {
+        *self.queue_depth.lock().unwrap() = queue_depth;
+    }
+
+    // Call right after `ev` was written to the virtual device
+    pub fn record(&self, ev: &InputEvent) {
+        let now = Instant::now();
+        // events made up by the daemon have no kernel timestamp
+        let latency = match ev.timestamp() {
+            SystemTime::UNIX_EPOCH => None,
+            stamp => SystemTime::now().duration_since(stamp).ok(),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.roll(now);
+        inner.forwarded += 1;
+        inner.last_event = Some(now);
+        inner.current.events += 1;
+        if let Some(latency) = latency {
+            inner.current.latency_sum += latency;
+            inner.current.latency_samples += 1;
+            inner.current.latency_max = inner.current.latency_max.max(latency);
+        }
+    }
+
+    // Call once a whole frame was written, with how many writes it took
+    pub fn record_frame(&self, writes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.frames += 1;
+        inner.writes += writes;
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        // an idle sink still has to see its rate drop
+        inner.roll(now);
+
+        StatsSnapshot {
+            events_forwarded: inner.forwarded,
+            events_per_second: inner.events_per_second,
+            last_event_ms_ago: inner.last_event.map(|t| (now - t).as_millis() as u64),
+            queue_depth: self.queue_depth.lock().unwrap().load(Ordering::Relaxed),
+            latency_avg_us: inner.latency_avg.map(|d| d.as_micros() as u64),
+            latency_max_us: inner.latency_max.map(|d| d.as_micros() as u64),
+            frames_forwarded: inner.frames,
+            writes_per_frame: (inner.frames > 0).then(|| inner.writes as f64 / inner.frames as f64),
+        }
+    }
+}
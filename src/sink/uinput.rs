@@ -1,8 +1,28 @@
 use crate::{
-    sink::Sink,
-    source::{OpenedEventSource, SourceCaps},
+    monitor::{self, Stage},
+    sink::{
+        axes::AxisMap,
+        ff::{self, FfSettings, Passthrough},
+        frame::{write_frame, Frame},
+        motion::{self, MotionDevice},
+        profile::{self, Profile},
+        state::PadState,
+        stats::{SinkStats, StatsSnapshot},
+        Rebound,
+        Sink,
+    },
+    source::{event, OpenedEventSource, SourceCaps, SourceIdentity, StopFlag, POLL_INTERVAL},
+};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+        Mutex,
+    },
+    thread::JoinHandle,
 };
-use std::sync::Arc;
 use evdev::{
     uinput::{
         VirtualDeviceBuilder,
@@ -10,107 +30,353 @@ use evdev::{
     },
     UinputAbsSetup,
     AbsInfo,
-    Key,
-    InputId,
-    AbsoluteAxisType,
+    AttributeSet,
+    FFEffectType,
+    InputEventKind,
+    Synchronization,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::{info, trace, warn};
+
+// What the sink tells about its source, switched over by the worker once
+// it reads from a new one
+struct Bound {
+    id: String,
+    name: String,
+    caps: SourceCaps,
+    devices: Vec<SourceIdentity>,
+}
+
+impl Bound {
+    fn of(src: &OpenedEventSource) -> Self {
+        Self { id: src.id.clone(), name: src.name.clone(), caps: src.caps, devices: src.devices.clone() }
+    }
+}
 
 pub struct UinputSink {
-    source_name: String,
-    source_caps: SourceCaps,
-    _ptr: Arc<()>,
+    profile: &'static Profile,
+    source: Arc<Mutex<Bound>>,
+    source_lost: Arc<AtomicBool>,
+    lost_reported: AtomicBool,
+    stop: StopFlag,
+    stats: Arc<SinkStats>,
+    ff: Arc<FfSettings>,
+    // whether to forward motion sensors, off unless asked for
+    motion: Arc<AtomicBool>,
+    // new sources for the worker to switch to
+    rebind: Mutex<Sender<Rebind>>,
+    worker: Option<JoinHandle<()>>,
     //todo
 }
 
-static MAX_OUT_ANALOG: i32 = 32767;
-static MIN_OUT_ANALOG: i32 = -32768;
+impl Drop for UinputSink {
+    fn drop(&mut self) {
+        self.stop.stop();
+        // the virtual device and the source are gone once the worker is
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Threads serving the virtual pad on behalf of its current source. What
+// they work with outlives the source and carries over to the next one.
+struct Helpers {
+    dst: Arc<Mutex<VirtualDevice>>,
+    profile: &'static Profile,
+    // None while a thread has them, or if the pad has no such thing
+    rumble: Option<Passthrough>,
+    motion: Option<MotionDevice>,
+    // the motion setting, and what start() last made of it
+    motion_wanted: Arc<AtomicBool>,
+    motion_on: bool,
+    stop: StopFlag,
+    ff_thread: Option<JoinHandle<Passthrough>>,
+    motion_thread: Option<JoinHandle<MotionDevice>>,
+}
 
-static MIN_OUT_HAT: i32 = -1;
-static MAX_OUT_HAT: i32 = 1;
+impl Helpers {
+    fn start(&mut self, src: &OpenedEventSource) {
+        self.stop = StopFlag::default();
+        if let Some(mut ff) = self.rumble.take() {
+            ff.retarget(&src.rumble);
+            let dst = Arc::clone(&self.dst);
+            let stop = self.stop.clone();
+            self.ff_thread = Some(std::thread::spawn(move || ff::ff_worker(dst, ff, stop)));
+        }
+        self.motion_on = self.motion_wanted.load(Ordering::Relaxed);
+        if !self.motion_on {
+            self.motion = None;
+        } else if self.motion.is_none() && !src.motion.is_empty() {
+            // a pad without its motion sensors is still worth having
+            let name = format!("{} {}", src.name, self.profile.motion.suffix);
+            self.motion = MotionDevice::new(&name, self.profile.input_id(), self.profile.motion)
+                .map_err(|e| warn!("No motion sensors for {}: {}", src.name, e))
+                .ok();
+        }
+        if let Some(node) = src.motion.first() {
+            if let Some(dev) = self.motion.take() {
+                let (node, profile, stop) = (node.clone(), self.profile.motion, self.stop.clone());
+                self.motion_thread = Some(std::thread::spawn(move || motion::motion_worker(node, dev, profile, stop)));
+            }
+        }
+    }
 
-static MIN_OUT_TRIG: i32 = 0;
-static MAX_OUT_TRIG: i32 = 255;
+    fn motion_changed(&self) -> bool {
+        self.motion_on != self.motion_wanted.load(Ordering::Relaxed)
+    }
 
-fn sink_worker(src: OpenedEventSource, mut dst: VirtualDevice, ptr: Arc<()>) {
-    loop {
-        // if the UinputSink was dropped quit
-        if Arc::strong_count(&ptr) < 2 {
-            return;
+    fn stop(&mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.ff_thread.take() {
+            self.rumble = thread.join().ok();
         }
-        match src.chan.recv() {
-            Ok(ev) => dst.emit(&[ev]).unwrap(),
-            Err(_) => return, // assume we got dropped
+        if let Some(thread) = self.motion_thread.take() {
+            self.motion = thread.join().ok();
         }
     }
 }
 
-impl Sink for UinputSink {
-    fn name(&self) -> &'static str {
-        "Gamepad device"
+// A new source, and where to say once the worker reads from it
+type Rebind = (OpenedEventSource, Sender<()>);
+
+// Writes whatever puts the pad back at rest
+fn release(dst: &Mutex<VirtualDevice>, state: &mut PadState) {
+    let neutral = state.neutral();
+    if !neutral.is_empty() {
+        let _ = write_frame(&*dst.lock().unwrap(), &neutral);
     }
-    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
-        let mut keys = evdev::AttributeSet::<Key>::new();
-        keys.insert(Key::BTN_SOUTH);
-        keys.insert(Key::BTN_EAST);
-        keys.insert(Key::BTN_NORTH);
-        keys.insert(Key::BTN_WEST);
-        keys.insert(Key::BTN_TL);
-        keys.insert(Key::BTN_TR);
-        keys.insert(Key::BTN_SELECT);
-        keys.insert(Key::BTN_START);
-        keys.insert(Key::BTN_MODE);
-        keys.insert(Key::BTN_THUMBL);
-        keys.insert(Key::BTN_THUMBR);
-
-        let input_id = InputId::new(evdev::BusType::BUS_USB, 0x045e, 0x028e, 0x2137);
-
-        let abs_analogs = AbsInfo::new(0, MIN_OUT_ANALOG, MAX_OUT_ANALOG, 16, 256, 0);
-        let abs_x = UinputAbsSetup::new(AbsoluteAxisType::ABS_X, abs_analogs);
-        let abs_y = UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, abs_analogs);
-        let abs_rx = UinputAbsSetup::new(AbsoluteAxisType::ABS_RX, abs_analogs);
-        let abs_ry = UinputAbsSetup::new(AbsoluteAxisType::ABS_RY, abs_analogs);
-
-        let abs_triggers = AbsInfo::new(0, MIN_OUT_TRIG, MAX_OUT_TRIG, 0, 0, 0);
-        let abs_z = UinputAbsSetup::new(AbsoluteAxisType::ABS_Z, abs_triggers);
-        let abs_rz = UinputAbsSetup::new(AbsoluteAxisType::ABS_RZ, abs_triggers);
-
-        let abs_hat = AbsInfo::new(0, MIN_OUT_HAT, MAX_OUT_HAT, 0, 0, 0);
-        let abs_hat_x = UinputAbsSetup::new(AbsoluteAxisType::ABS_HAT0X, abs_hat);
-        let abs_hat_y = UinputAbsSetup::new(AbsoluteAxisType::ABS_HAT0Y, abs_hat);
-
-        let uinput_handle = VirtualDeviceBuilder::new().unwrap()
-            .name(source.name.as_str().as_bytes())
-            .input_id(input_id)
-            .with_keys(&keys)?
-            .with_absolute_axis(&abs_x)?
-            .with_absolute_axis(&abs_y)?
-            .with_absolute_axis(&abs_rx)?
-            .with_absolute_axis(&abs_ry)?
-            .with_absolute_axis(&abs_z)?
-            .with_absolute_axis(&abs_rz)?
-            .with_absolute_axis(&abs_hat_x)?
-            .with_absolute_axis(&abs_hat_y)?
-            .build().unwrap();
-
-        // TODO: map abs axis values
-
-        let ptr = Arc::new(());
-        let ptr2 = Arc::clone(&ptr);
-
-        let out = Box::new(UinputSink{
-            source_name: source.name.clone(),
-            source_caps: source.caps,
-            _ptr: ptr,
+}
+
+fn sink_worker(mut src: OpenedEventSource, mut helpers: Helpers, bound: Arc<Mutex<Bound>>, rebind: Receiver<Rebind>,
+    stop: StopFlag, lost: Arc<AtomicBool>, stats: Arc<SinkStats>)
+{
+    let dst = Arc::clone(&helpers.dst);
+    let profile = helpers.profile;
+    let mut axes = AxisMap::new(&src.axes, profile);
+    let mut state = PadState::new(profile);
+    let mut frame = Frame::default();
+    helpers.start(&src);
+    let mut pending = None;
+
+    while !stop.is_stopped() {
+        if let Some((new, ack)) = pending.take().or_else(|| rebind.try_recv().ok()) {
+            // games must not see the old pad's buttons held forever
+            release(&dst, &mut state);
+            frame.take();
+            helpers.stop();
+            info!("Source {} of a sink replaced by {}", src.id, new.id);
+            // the pad's FF bits were fixed when it was made
+            match (helpers.rumble.is_some(), new.rumble.is_empty()) {
+                (true, true) => info!("{} can't rumble, rumble of its sink goes nowhere", new.name),
+                (false, false) => warn!("Sink of {} was made without rumble, re-create it to get rumble", new.name),
+                _ => {},
+            }
+            axes = AxisMap::new(&new.axes, profile);
+            stats.set_queue_depth(new.chan.depth());
+            // the old source is released right here
+            src = new;
+            *bound.lock().unwrap() = Bound::of(&src);
+            helpers.start(&src);
+            let _ = ack.send(());
+            continue;
+        }
+        if helpers.motion_changed() {
+            helpers.stop();
+            helpers.start(&src);
+        }
+
+        let ev = match src.chan.recv_timeout(POLL_INTERVAL) {
+            Ok(ev) => ev,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // the old source may have gone just as a new one was sent
+            Err(RecvTimeoutError::Disconnected) => match rebind.try_recv() {
+                Ok(new) => {
+                    pending = Some(new);
+                    continue;
+                },
+                Err(_) => break,
+            },
+        };
+        if ev.kind() != InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
+            frame.push(axes.apply(ev));
+            continue;
+        }
+        if frame.is_empty() {
+            continue;
+        }
+
+        let events = frame.take();
+        match write_frame(&*dst.lock().unwrap(), &events) {
+            Ok(writes) => stats.record_frame(writes),
+            Err(_) => break,
+        }
+        for ev in &events {
+            state.record(ev);
+            stats.record(ev);
+            trace!("{} -> {:?}", src.id, ev);
+            monitor::publish(Stage::Output, &src.id, ev);
+        }
+    }
+
+    // if the UinputSink was dropped just quit, otherwise the source went away
+    if !stop.is_stopped() {
+        info!("Source {} of a sink went away", src.id);
+        lost.store(true, Ordering::Relaxed);
+    }
+
+    // games may keep reading the pad for a moment, nothing may stay held
+    release(&dst, &mut state);
+    helpers.stop();
+
+    // destroy the virtual pad before giving the real one back, so nothing
+    // sees both at once
+    drop(helpers);
+    drop(dst);
+    drop(src);
+}
+
+impl UinputSink {
+    pub fn with_profile(source: OpenedEventSource, profile: &'static Profile) -> Result<Box<dyn Sink>> {
+        let mut keys = evdev::AttributeSet::new();
+        for key in profile.keys {
+            keys.insert(*key);
+        }
+
+        let mut builder = VirtualDeviceBuilder::new()?
+            .name(source.name.as_bytes())
+            .input_id(profile.input_id())
+            .with_keys(&keys)?;
+        for (axis, range) in profile.axes {
+            let info = AbsInfo::new(0, range.min, range.max, range.fuzz, range.flat, 0);
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(*axis, info))?;
+        }
+        // only offer rumble games can actually feel
+        if !source.rumble.is_empty() {
+            let mut ff_types = AttributeSet::new();
+            ff_types.insert(FFEffectType::FF_RUMBLE);
+            builder = builder.with_ff(&ff_types)?
+                .with_ff_effects_max(ff::MAX_EFFECTS);
+        }
+        let mut uinput_handle = builder.build()?;
+        event::mark_own(&uinput_handle.get_syspath()?);
+
+        let stop = StopFlag::default();
+        let stop2 = stop.clone();
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost2 = Arc::clone(&lost);
+        let stats = Arc::new(SinkStats::new(source.chan.depth()));
+        let stats2 = Arc::clone(&stats);
+        let ff = Arc::new(FfSettings::default());
+        let motion = Arc::new(AtomicBool::new(false));
+        let (rebind, rebind_rx) = mpsc::channel();
+        let bound = Arc::new(Mutex::new(Bound::of(&source)));
+        let bound2 = Arc::clone(&bound);
+        let helpers = Helpers {
+            dst: Arc::new(Mutex::new(uinput_handle)),
+            profile,
+            rumble: (!source.rumble.is_empty()).then(|| Passthrough::new(Arc::clone(&ff))),
+            motion: None,
+            motion_wanted: Arc::clone(&motion),
+            motion_on: false,
+            stop: StopFlag::default(),
+            ff_thread: None,
+            motion_thread: None,
+        };
+
+        let mut out = Box::new(UinputSink{
+            profile,
+            source: bound,
+            source_lost: lost,
+            lost_reported: AtomicBool::new(false),
+            stop,
+            stats,
+            ff,
+            motion,
+            rebind: Mutex::new(rebind),
+            worker: None,
         });
 
-        std::thread::spawn(|| sink_worker(source, uinput_handle, ptr2));
+        out.worker = Some(std::thread::spawn(move || sink_worker(source, helpers, bound2, rebind_rx, stop2, lost2, stats2)));
         Ok(out)
     }
+}
+
+pub fn new_xbox_series(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+    UinputSink::with_profile(source, &profile::XBOX_SERIES)
+}
+
+pub fn new_dualshock_4(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+    UinputSink::with_profile(source, &profile::DUALSHOCK_4)
+}
+
+pub fn new_dualsense(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+    UinputSink::with_profile(source, &profile::DUALSENSE)
+}
+
+pub fn new_switch_pro(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+    UinputSink::with_profile(source, &profile::SWITCH_PRO)
+}
+
+impl Sink for UinputSink {
+    fn name(&self) -> &'static str {
+        self.profile.name
+    }
+    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        UinputSink::with_profile(source, &profile::XBOX_360)
+    }
     fn source_name(&self) -> String {
-        self.source_name.clone()
+        self.source.lock().unwrap().name.clone()
+    }
+    fn source_id(&self) -> String {
+        self.source.lock().unwrap().id.clone()
     }
     fn source_caps(&self) -> SourceCaps {
-        self.source_caps
+        self.source.lock().unwrap().caps
+    }
+    fn source_devices(&self) -> Vec<SourceIdentity> {
+        self.source.lock().unwrap().devices.clone()
+    }
+    fn take_source_lost(&self) -> bool {
+        self.source_lost.load(Ordering::Relaxed) && !self.lost_reported.swap(true, Ordering::Relaxed)
+    }
+    fn is_healthy(&self) -> bool {
+        let finished = self.worker.as_ref().is_none_or(|w| w.is_finished());
+        !finished || self.source_lost.load(Ordering::Relaxed)
+    }
+    fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+    fn settings(&self) -> BTreeMap<String, String> {
+        let mut ret = BTreeMap::new();
+        ret.insert("rumble".to_string(), if self.ff.enabled() { "on" } else { "off" }.to_string());
+        ret.insert("rumble_intensity".to_string(), self.ff.intensity().to_string());
+        ret.insert("motion".to_string(), if self.motion.load(Ordering::Relaxed) { "on" } else { "off" }.to_string());
+        ret
+    }
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "rumble" | "rumble_intensity" => self.ff.set(key, value),
+            "motion" => {
+                let on = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!("motion is on or off, not {}", value),
+                };
+                self.motion.store(on, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => bail!("{} has no setting {}", self.name(), key),
+        }
+    }
+    fn rebind(&mut self, source: OpenedEventSource) -> Result<Rebound> {
+        if self.source_lost.load(Ordering::Relaxed) {
+            bail!("The sink lost its source and is going away");
+        }
+        let (ack, swapped) = mpsc::channel();
+        if self.rebind.lock().unwrap().send((source, ack)).is_err() {
+            bail!("The sink stopped working");
+        }
+        Ok(Rebound(swapped))
     }
 }
@@ -1,19 +1,39 @@
 use evdev::{
     Device,
-    InputEvent,
+    FFEffectType,
+    InputId,
     Key,
     AbsoluteAxisType,
+    PropType,
 };
+use log::{debug, trace};
+use crate::monitor::{self, Stage};
 use crate::source::{
+    event_channel,
+    remapped,
+    EventReceiver,
+    EventSender,
     EventSource,
+    AxisRange,
+    leds,
     SourceCaps,
+    StopFlag,
+    POLL_INTERVAL,
     quirks_db::{
         self,
         InputRemap,
     },
 };
+use nix::poll::{poll, PollFd, PollFlags};
+use anyhow::Result;
 use std::{
-    sync::mpsc::{channel, Sender, Receiver},
+    collections::BTreeSet,
+    os::unix::io::AsRawFd,
+    sync::{
+        mpsc::{channel, Receiver},
+        Mutex,
+    },
+    thread::JoinHandle,
     path::{Path, PathBuf},
     fs,
 };
@@ -32,7 +52,7 @@ fn usb_manufacturer_product(input: String) -> Option<String> {
             .map(|v| v.to_string())
             .collect::<Vec<String>>();
 
-        if let Some((pci_id, usb_path)) = path_vec.get(0).zip(path_vec.get(1)) {
+        if let Some((pci_id, usb_path)) = path_vec.first().zip(path_vec.get(1)) {
             let path: PathBuf = ["/sys/bus/pci/devices/", pci_id].iter().collect();
             if !path.exists() {
                 return None;
@@ -57,7 +77,7 @@ fn usb_manufacturer_product(input: String) -> Option<String> {
             let manufacturer = manufacturer_raw.trim();
             let product = product_raw.trim();
 
-            if product.starts_with(&manufacturer) {
+            if product.starts_with(manufacturer) {
                 return Some(product.to_string());
             }
 
@@ -97,7 +117,7 @@ fn get_device_quirks(dev: &Device, path: &Path) -> Vec<EvdevQuirks> {
 
     if let Some(actual_dmi_quirk) = dmi_quirk {
         ret.extend(actual_dmi_quirk.remap_codes.into_iter()
-                   .map(|v| EvdevQuirks::RemapCodes(v)));
+                   .map(EvdevQuirks::RemapCodes));
     }
 
     if let Some(name) = dev.name() {
@@ -111,26 +131,62 @@ fn get_device_quirks(dev: &Device, path: &Path) -> Vec<EvdevQuirks> {
                 InputRemap::KeyToAbs(Key::BTN_DPAD_DOWN, AbsoluteAxisType::ABS_HAT0Y),
                 InputRemap::KeyToAbs(Key::BTN_DPAD_UP, AbsoluteAxisType::ABS_HAT0Y),
             ];
-            ret.extend(remaps.into_iter().map(|v| EvdevQuirks::RemapCodes(v)));
+            ret.extend(remaps.into_iter().map(EvdevQuirks::RemapCodes));
         } else {
             let remaps = vec![
                 InputRemap::KeyToAbs(Key::BTN_TR2, AbsoluteAxisType::ABS_RZ),
             ];
-            ret.extend(remaps.into_iter().map(|v| EvdevQuirks::RemapCodes(v)));
+            ret.extend(remaps.into_iter().map(EvdevQuirks::RemapCodes));
         }
     }
 
     ret
 }
 
+// Names like input42 of the input devices this daemon created. The kernel
+// never hands out a number twice, so destroyed ones may stay in here.
+static OWN_DEVICES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// Keeps a virtual device of ours, found by its sysfs path, from being taken
+// for a source. Profiles copy real pads' IDs, so those can't tell.
+pub fn mark_own(syspath: &Path) {
+    if let Some(name) = syspath.file_name() {
+        OWN_DEVICES.lock().unwrap().insert(name.to_string_lossy().into_owned());
+    }
+}
+
+// Whether the event node `path` belongs to a device from mark_own
+fn is_own(sysfs: &Path, path: &Path) -> bool {
+    let node = match path.file_name() {
+        Some(n) => n,
+        None => return false,
+    };
+    fs::read_link(sysfs.join("class/input").join(node).join("device"))
+        .ok()
+        .and_then(|dev| dev.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .is_some_and(|name| OWN_DEVICES.lock().unwrap().contains(&name))
+}
+
+pub fn is_gamepad(path: &Path, device: &Device) -> bool {
+    if !device.supported_keys().is_some_and(|k| k.contains(Key::BTN_SOUTH)) 
+    && !device.supported_keys().is_some_and(|k| k.contains(Key::BTN_THUMBL)) {
+        return false;
+    }
+
+    // skip our own virtual devices
+    !is_own(Path::new(leds::SYSFS), path)
+}
+
 #[allow(dead_code)]
 pub struct Evdev {
     device: Device,
+    node: String,
+    dev_path: PathBuf,
     override_name: Option<String>,
     remap_events: Vec<InputRemap>,
     sibling_device: Option<Device>,
-    tx: Sender<InputEvent>,
-    rx: Option<Receiver<InputEvent>>,
+    tx: EventSender,
+    rx: Option<EventReceiver>,
 }
 
 unsafe impl Send for Evdev{}
@@ -138,30 +194,25 @@ unsafe impl Sync for Evdev{}
 
 impl Drop for Evdev {
     fn drop(&mut self) {
-        println!("Ungrabbing device");
-        self.device.ungrab().unwrap();
+        debug!("Ungrabbing {}", self.node);
+        // fails if the device is already gone, nothing to release then
+        let _ = self.device.ungrab();
     }
 }
 
 impl Evdev {
-    fn new(path: PathBuf, mut device: Device) -> Option<Self> {
-        // check for gamepads
-        if !device.supported_keys().map_or(false, |k| k.contains(Key::BTN_SOUTH)) 
-        && !device.supported_keys().map_or(false, |k| k.contains(Key::BTN_THUMBL)) {
-            return None;
-        }
-
-        if device.input_id().version() == 0x2137 {
+    fn new(path: PathBuf, device: Device) -> Option<Self> {
+        if !is_gamepad(&path, &device) {
             return None;
         }
 
-        device.grab().ok()?;
         //fs::remove_file(&path).ok()?;
 
         let mut override_name = None;
         let mut remap_events = Vec::new();
 
         let quirks = get_device_quirks(&device, &path);
+        trace!("Quirks for {}: {:?}", path.display(), quirks);
 
         for quirk in quirks {
             match quirk {
@@ -171,9 +222,14 @@ impl Evdev {
             };
         }
 
-        let (tx, rx) = channel();
+        let node = path.file_name()?.to_string_lossy().into_owned();
+        let dev_path = path;
+
+        let (tx, rx) = event_channel();
         Some(Self {
             device,
+            node,
+            dev_path,
             override_name,
             remap_events,
             sibling_device: None,
@@ -183,6 +239,21 @@ impl Evdev {
     }
 }
 
+// hid-nintendo and hid-playstation put the IMU on a device of its own, with
+// the same uniq and phys as the pad
+fn find_motion_node(pad: &Device) -> Option<PathBuf> {
+    let uniq = pad.unique_name().unwrap_or_default();
+    let phys = pad.physical_path().unwrap_or_default();
+    if uniq.is_empty() && phys.is_empty() {
+        return None;
+    }
+    evdev::enumerate()
+        .find(|(_, d)| d.properties().contains(PropType::ACCELEROMETER)
+            && d.unique_name().unwrap_or_default() == uniq
+            && d.physical_path().unwrap_or_default() == phys)
+        .map(|(path, _)| path)
+}
+
 pub fn enumerate() -> (Vec<Box<dyn EventSource>>, Receiver<Evdev>) {
     let (_tx, rx) = channel();
     let tmp: Vec<Evdev> = evdev::enumerate()
@@ -198,15 +269,35 @@ pub fn enumerate() -> (Vec<Box<dyn EventSource>>, Receiver<Evdev>) {
     (ret, rx)
 }
 
-fn worker(mut dev: Evdev) {
+fn worker(mut dev: Evdev, stop: StopFlag) {
     let raw_dev = &mut dev.device;
     let skip_remap = dev.remap_events.is_empty();
+    let mut fds = [PollFd::new(raw_dev.as_raw_fd(), PollFlags::POLLIN)];
     //let skip_mult = true; // TODO
     loop {
-        for ev in raw_dev.fetch_events().unwrap() {
+        if stop.is_stopped() {
+            return;
+        }
+        match poll(&mut fds, POLL_INTERVAL.as_millis() as i32) {
+            Ok(0) => continue,
+            Ok(_) => (),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => return,
+        }
+
+        let events = match raw_dev.fetch_events() {
+            Ok(v) => v,
+            Err(e) => {
+                // device was unplugged
+                debug!("Stopped reading {}: {}", dev.node, e);
+                return;
+            },
+        };
+        for ev in events {
+            monitor::publish(Stage::Raw, &dev.node, &ev);
             if !skip_remap {
                 if let Some(new) = dev.remap_events.iter().find_map(|v| v.apply_quirk(ev)) {
-                    if dev.tx.send(new).is_err() {
+                    if dev.tx.send(remapped(new, &ev)).is_err() {
                         return;
                     }
                     continue;
@@ -220,13 +311,20 @@ fn worker(mut dev: Evdev) {
 }
 
 impl EventSource for Evdev {
-    fn make_tx(&self) -> Sender<InputEvent> {
-        self.tx.clone()
+    fn start_ev(mut self: Box<Evdev>, stop: StopFlag) -> Result<(EventReceiver, JoinHandle<()>)> {
+        // fails if a sink or another pairing already has the device
+        self.device.grab()?;
+        debug!("Grabbed {}", self.node);
+        self.start_ev_shared(stop)
     }
-    fn start_ev(mut self: Box<Evdev>) -> Receiver<InputEvent> {
+    fn start_ev_shared(mut self: Box<Evdev>, stop: StopFlag) -> Result<(EventReceiver, JoinHandle<()>)> {
         let rx = self.rx.take();
-        std::thread::spawn(|| worker(*self));
-        rx.unwrap()
+        // the device is ungrabbed when the worker returns and drops it
+        let thread = std::thread::spawn(|| worker(*self, stop));
+        Ok((rx.unwrap(), thread))
+    }
+    fn id(&self) -> String {
+        self.node.clone()
     }
     fn name(self: &Evdev) -> String {
         if let Some(n) = self.override_name.clone() {
@@ -240,6 +338,40 @@ impl EventSource for Evdev {
     fn path(self: &Evdev) -> String {
         self.device.physical_path().unwrap_or("Unknown").to_string()
     }
+    fn uniq(&self) -> String {
+        self.device.unique_name().unwrap_or_default().to_string()
+    }
+    fn input_id(&self) -> InputId {
+        self.device.input_id()
+    }
+    fn rumble_node(&self) -> Option<PathBuf> {
+        let ff = self.device.supported_ff()?;
+        ff.contains(FFEffectType::FF_RUMBLE).then(|| self.dev_path.clone())
+    }
+    fn motion_node(&self) -> Option<PathBuf> {
+        find_motion_node(&self.device)
+    }
+    fn axes(&self) -> Vec<(AbsoluteAxisType, AxisRange)> {
+        let mut ret = Vec::new();
+        if let (Some(supported), Ok(state)) = (self.device.supported_absolute_axes(), self.device.get_abs_state()) {
+            for axis in supported.iter() {
+                let info = state[axis.0 as usize];
+                ret.push((axis, AxisRange {
+                    min: info.minimum,
+                    max: info.maximum,
+                    fuzz: info.fuzz,
+                    flat: info.flat,
+                }));
+            }
+        }
+
+        // remapped events replace whatever the device had on that axis
+        for (axis, range) in self.remap_events.iter().filter_map(|v| v.output_axis()) {
+            ret.retain(|(a, _)| *a != axis);
+            ret.push((axis, range));
+        }
+        ret
+    }
     fn get_capabilities(&self) -> SourceCaps {
         if let Some(keys) = self.device.supported_keys() {
             if keys.contains(Key::BTN_SOUTH) {
@@ -260,3 +392,36 @@ impl EventSource for Evdev {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink};
+
+    // event node whose device lives at devices/virtual/input/<input>
+    fn fake_node(root: &Path, node: &str, input: &str) -> PathBuf {
+        let dev = root.join("devices/virtual/input").join(input);
+        fs::create_dir_all(&dev).unwrap();
+        let class = root.join("class/input").join(node);
+        fs::create_dir_all(&class).unwrap();
+        symlink(&dev, class.join("device")).unwrap();
+        PathBuf::from("/dev/input").join(node)
+    }
+
+    #[test]
+    fn own_pads_are_recognized_whatever_their_ids() {
+        let root = env::temp_dir().join(format!("rinputer4-own-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        // a DualShock 4 sink's pad carries Sony's IDs and version 0x8111,
+        // just like the real thing plugged in next to it
+        let virtual_pad = fake_node(&root, "event21", "input90");
+        let real_pad = fake_node(&root, "event20", "input89");
+        mark_own(&root.join("devices/virtual/input/input90"));
+
+        assert!(is_own(&root, &virtual_pad));
+        assert!(!is_own(&root, &real_pad));
+        assert!(!is_own(&root, Path::new("/dev/input/event99")));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
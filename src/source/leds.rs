@@ -0,0 +1,139 @@
+use std::{
+    fs,
+    io,
+    path::Path,
+};
+
+pub static SYSFS: &str = "/sys";
+
+// Which of the player LEDs are lit for each player, as the kernel drivers
+// light them on their own. Bit n is LED player-(n + 1).
+const NINTENDO_PATTERNS: [u8; 8] = [0b0001, 0b0011, 0b0111, 0b1111, 0b1001, 0b0101, 0b1101, 0b0110];
+const DUALSENSE_PATTERNS: [u8; 5] = [0b00100, 0b01010, 0b10101, 0b11011, 0b11111];
+// lightbar colors, DS4 style: blue, red, green, pink, orange, teal, white
+const COLORS: [[u8; 3]; 7] = [
+    [0, 0, 255], [255, 0, 0], [0, 255, 0], [255, 0, 255],
+    [255, 128, 0], [0, 255, 255], [255, 255, 255],
+];
+
+enum Led {
+    // LED n of a row of player LEDs, counting from 0
+    Player(u8),
+    // a multicolor lightbar
+    Rgb,
+    // one color of a lightbar made of separate LEDs
+    Channel(usize),
+    // the ring of an Xbox 360 pad, brightness picks the pattern
+    Xpad,
+}
+
+fn classify(name: &str) -> Option<Led> {
+    if let Some(n) = name.rsplit_once(":player-").and_then(|(_, n)| n.parse::<u8>().ok()) {
+        return n.checked_sub(1).map(Led::Player);
+    }
+    if name.starts_with("xpad") {
+        return Some(Led::Xpad);
+    }
+    match name.rsplit(':').next()? {
+        "indicator" if name.contains(":rgb:") => Some(Led::Rgb),
+        "red" => Some(Led::Channel(0)),
+        "green" => Some(Led::Channel(1)),
+        "blue" => Some(Led::Channel(2)),
+        _ => None,
+    }
+}
+
+fn pick<T: Copy>(options: &[T], player: u8) -> T {
+    options[(player.max(1) as usize - 1) % options.len()]
+}
+
+// Shows `player`, counted from 1, on the LEDs of the input device `node`
+// (like event5) under the sysfs mounted at `sysfs`. Returns how many LEDs
+// were set, devices without any are left alone.
+pub fn show_player(sysfs: &Path, node: &str, player: u8) -> io::Result<usize> {
+    // the LEDs belong to the HID device or USB interface above the input device
+    let dir = sysfs.join("class/input").join(node).join("device/device/leds");
+    let leds: Vec<(Led, std::path::PathBuf)> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| Some((classify(&e.file_name().to_string_lossy())?, e.path())))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let player_leds = leds.iter().filter(|(l, _)| matches!(l, Led::Player(_))).count();
+    let pattern = if player_leds > 4 {
+        pick(&DUALSENSE_PATTERNS, player)
+    } else {
+        pick(&NINTENDO_PATTERNS, player)
+    };
+    let color = pick(&COLORS, player);
+
+    for (led, path) in leds.iter() {
+        match led {
+            Led::Player(n) => fs::write(path.join("brightness"), if pattern >> n & 1 == 1 { "1" } else { "0" })?,
+            Led::Rgb => {
+                fs::write(path.join("multi_intensity"), format!("{} {} {}", color[0], color[1], color[2]))?;
+                fs::write(path.join("brightness"), "255")?;
+            },
+            Led::Channel(c) => fs::write(path.join("brightness"), color[*c].to_string())?,
+            // 6 to 9 light up quarter 1 to 4
+            Led::Xpad => fs::write(path.join("brightness"), (6 + (player.max(1) - 1) % 4).to_string())?,
+        }
+    }
+    Ok(leds.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn fake_leds(root: &Path, node: &str, names: &[&str]) {
+        for name in names {
+            let led = root.join("class/input").join(node).join("device/device/leds").join(name);
+            fs::create_dir_all(&led).unwrap();
+            fs::write(led.join("brightness"), "0").unwrap();
+        }
+    }
+
+    fn brightness(root: &Path, node: &str, name: &str) -> String {
+        let led = root.join("class/input").join(node).join("device/device/leds").join(name);
+        fs::read_to_string(led.join("brightness")).unwrap()
+    }
+
+    #[test]
+    fn lights_player_leds_and_lightbars() {
+        let root = env::temp_dir().join(format!("rinputer4-sysfs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let switch: Vec<String> = (1..=4).map(|n| format!("0005:057E:2009.0001:green:player-{}", n)).collect();
+        fake_leds(&root, "event3", &switch.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+        assert_eq!(show_player(&root, "event3", 3).unwrap(), 4);
+        let lit: Vec<String> = switch.iter().map(|n| brightness(&root, "event3", n)).collect();
+        assert_eq!(lit, ["1", "1", "1", "0"]);
+
+        let mut dualsense: Vec<String> = (1..=5).map(|n| format!("input9:white:player-{}", n)).collect();
+        dualsense.push("input9:rgb:indicator".to_string());
+        fake_leds(&root, "event9", &dualsense.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+        assert_eq!(show_player(&root, "event9", 2).unwrap(), 6);
+        let lit: Vec<String> = dualsense[..5].iter().map(|n| brightness(&root, "event9", n)).collect();
+        assert_eq!(lit, ["0", "1", "0", "1", "0"]);
+        let bar = root.join("class/input/event9/device/device/leds/input9:rgb:indicator/multi_intensity");
+        assert_eq!(fs::read_to_string(bar).unwrap(), "255 0 0");
+
+        fake_leds(&root, "event4", &["xpad0"]);
+        show_player(&root, "event4", 2).unwrap();
+        assert_eq!(brightness(&root, "event4", "xpad0"), "7");
+
+        fake_leds(&root, "event5", &["0003:054C:05C4.0002:red", "0003:054C:05C4.0002:blue", "0003:054C:05C4.0002:global"]);
+        assert_eq!(show_player(&root, "event5", 1).unwrap(), 2);
+        assert_eq!(brightness(&root, "event5", "0003:054C:05C4.0002:blue"), "255");
+        assert_eq!(brightness(&root, "event5", "0003:054C:05C4.0002:global"), "0");
+
+        // no LEDs at all
+        assert_eq!(show_player(&root, "event7", 1).unwrap(), 0);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
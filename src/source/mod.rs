@@ -1,194 +1,495 @@
 use evdev::{
     Key,
     InputEvent,
+    InputId,
     InputEventKind,
     AbsoluteAxisType,
     EventType,
 };
 use std::{
     fmt,
-    sync::mpsc,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, SendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
+use serde::{Deserialize, Serialize};
 use anyhow::Result;
 
 mod quirks_db;
 pub mod event;
+pub mod leds;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SourceCaps {
     FullX360,
     DpadAndAB,
 }
 
+impl SourceCaps {
+    // Same as in JSON, used by the text protocol
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceCaps::FullX360 => "FullX360",
+            SourceCaps::DpadAndAB => "DpadAndAB",
+        }
+    }
+}
+
+// Range of an absolute axis, same meaning as in AbsInfo
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AxisRange {
+    pub min: i32,
+    pub max: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+}
+
+// How long pipeline threads block before checking whether they should quit
+pub static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often a pairing looks for sources that became free
+static RESCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+// Set once the consumer of an OpenedEventSource goes away. Every thread
+// feeding it watches this and quits, so grabbed devices get released.
+#[derive(Clone, Default)]
+pub struct StopFlag(Arc<AtomicBool>);
+
+impl StopFlag {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Channel between the stages of a source, counting events that were sent
+// but not received yet
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let depth = Arc::new(AtomicUsize::new(0));
+    (EventSender { tx, depth: Arc::clone(&depth) }, EventReceiver { rx, depth })
+}
+
+#[derive(Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<InputEvent>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl EventSender {
+    pub fn send(&self, ev: InputEvent) -> Result<(), SendError<InputEvent>> {
+        // counted before sending so the receiver never sees it go below zero
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(ev).inspect_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+}
+
+pub struct EventReceiver {
+    rx: mpsc::Receiver<InputEvent>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl EventReceiver {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<InputEvent, RecvTimeoutError> {
+        let ev = self.rx.recv_timeout(timeout)?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(ev)
+    }
+    #[cfg(test)]
+    pub fn try_recv(&self) -> Result<InputEvent, mpsc::TryRecvError> {
+        let ev = self.rx.try_recv()?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(ev)
+    }
+    // shared counter of queued events, readable from other threads
+    pub fn depth(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.depth)
+    }
+}
+
+// A remapped event, keeping the kernel timestamp of the one it replaces so
+// latency can still be measured at the end of the pipeline
+pub fn remapped(new: InputEvent, orig: &InputEvent) -> InputEvent {
+    let mut raw = *new.as_ref();
+    raw.time = orig.as_ref().time;
+    InputEvent::from(raw)
+}
+
+// What a physical device looks like, used to recognize it after a replug
+// or a daemon restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SourceIdentity {
+    pub name: String,
+    pub phys: String,
+    pub uniq: String,
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl SourceIdentity {
+    pub fn matches(&self, other: &SourceIdentity) -> bool {
+        if self.vendor != other.vendor || self.product != other.product {
+            return false;
+        }
+        // bluetooth pads have their MAC in uniq, wired ones only have a port
+        if !self.uniq.is_empty() || !other.uniq.is_empty() {
+            self.uniq == other.uniq
+        } else {
+            self.phys == other.phys
+        }
+    }
+}
+
 pub trait EventSource: Send + Sync {
-    fn start_ev(self: Box<Self>) -> mpsc::Receiver<InputEvent>;
-    fn make_tx(&self) -> mpsc::Sender<InputEvent>;
+    fn start_ev(self: Box<Self>, stop: StopFlag) -> Result<(EventReceiver, JoinHandle<()>)>;
+    // Like start_ev, but without taking the device away from everyone else
+    fn start_ev_shared(self: Box<Self>, stop: StopFlag) -> Result<(EventReceiver, JoinHandle<()>)>;
     
+    // stable for as long as the device stays plugged in
+    fn id(&self) -> String;
     fn name(&self) -> String;
     fn path(&self) -> String;
+    fn uniq(&self) -> String;
+    fn input_id(&self) -> InputId;
     
     fn get_capabilities(&self) -> SourceCaps;
+    // ranges of the axes this source sends, including ones made up by quirks
+    fn axes(&self) -> Vec<(AbsoluteAxisType, AxisRange)>;
+    // device node force feedback can be sent to, if the device can rumble
+    fn rumble_node(&self) -> Option<PathBuf>;
+    // node of the accelerometer/gyro device the driver creates next to the pad
+    fn motion_node(&self) -> Option<PathBuf>;
+
+    fn identity(&self) -> SourceIdentity {
+        SourceIdentity {
+            name: self.name(),
+            phys: self.path(),
+            uniq: self.uniq(),
+            vendor: self.input_id().vendor(),
+            product: self.input_id().product(),
+        }
+    }
 }
 
 pub struct OpenedEventSource {
+    pub id: String,
     pub name: String,
     pub path: String,
     pub caps: SourceCaps,
-    pub chan: mpsc::Receiver<InputEvent>,
-    pub chan_tx: mpsc::Sender<InputEvent>,
+    pub axes: Vec<(AbsoluteAxisType, AxisRange)>,
+    // nodes of the devices that can rumble, opened again for force feedback
+    pub rumble: Vec<PathBuf>,
+    // nodes of the motion sensors, the first one is what sinks forward
+    pub motion: Vec<PathBuf>,
+    // physical devices feeding this source
+    pub devices: Vec<SourceIdentity>,
+    pub chan: EventReceiver,
+    pub stop: StopFlag,
+    // threads feeding `chan`, joined on drop so the devices behind them
+    // are released by the time this is gone
+    pub threads: Vec<JoinHandle<()>>,
 }
 
-pub fn into_opened(input: Box<dyn EventSource>) -> OpenedEventSource {
-    OpenedEventSource {
-        name: input.name(),
-        path: input.path(),
-        caps: input.get_capabilities(),
-        chan_tx: input.make_tx(),
-        chan: input.start_ev(),
+impl Drop for OpenedEventSource {
+    fn drop(&mut self) {
+        self.stop.stop();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
     }
 }
 
+impl OpenedEventSource {
+    // A source fed by hand through the returned sender, with no devices
+    // behind it
+    #[cfg(test)]
+    pub fn fake() -> (EventSender, Self) {
+        let (tx, chan) = event_channel();
+        let source = OpenedEventSource {
+            id: "event9".to_string(),
+            name: "Test pad".to_string(),
+            path: "usb-test".to_string(),
+            caps: SourceCaps::FullX360,
+            axes: Vec::new(),
+            rumble: Vec::new(),
+            motion: Vec::new(),
+            devices: Vec::new(),
+            chan,
+            stop: StopFlag::default(),
+            threads: Vec::new(),
+        };
+        (tx, source)
+    }
+
+    // Waits for the next event while watching `stop`, which belongs to
+    // whoever consumes this source. None means either end went away.
+    pub fn recv_until(&self, stop: &StopFlag) -> Option<InputEvent> {
+        while !stop.is_stopped() {
+            match self.chan.recv_timeout(POLL_INTERVAL) {
+                Ok(ev) => return Some(ev),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        None
+    }
+}
+
+pub fn into_opened(input: Box<dyn EventSource>) -> Result<OpenedEventSource> {
+    open_with(input, true)
+}
+
+fn open_with(input: Box<dyn EventSource>, grab: bool) -> Result<OpenedEventSource> {
+    let stop = StopFlag::default();
+    let id = input.id();
+    let name = input.name();
+    let path = input.path();
+    let caps = input.get_capabilities();
+    let axes = input.axes();
+    let rumble = input.rumble_node().into_iter().collect();
+    let motion = input.motion_node().into_iter().collect();
+    let devices = vec![input.identity()];
+    let (chan, thread) = if grab {
+        input.start_ev(stop.clone())?
+    } else {
+        input.start_ev_shared(stop.clone())?
+    };
+    Ok(OpenedEventSource {
+        id,
+        name,
+        path,
+        caps,
+        axes,
+        rumble,
+        motion,
+        devices,
+        chan,
+        stop,
+        threads: vec![thread],
+    })
+}
+
+// Sets up a source bound without the pairing gesture, a lone Joy-Con is
+// assumed to be held sideways just like when paired with TR + TR2
+pub fn open_by_id(id: &str) -> Option<Result<OpenedEventSource>> {
+    let input = enumerate().into_iter().find(|v| v.id() == id)?;
+    Some(open_single(input))
+}
+
+// Reads a source without grabbing it, for watching what it sends
+pub fn open_shared(id: &str) -> Option<Result<OpenedEventSource>> {
+    let input = enumerate().into_iter().find(|v| v.id() == id)?;
+    Some(open_with(input, false))
+}
+
+pub fn open_single(input: Box<dyn EventSource>) -> Result<OpenedEventSource> {
+    let dev = into_opened(input)?;
+    if dev.name.contains("Joy-Con") {
+        Ok(sideways_joycon(dev))
+    } else {
+        Ok(dev)
+    }
+}
+
+pub fn sideways_joycon(dev: OpenedEventSource) -> OpenedEventSource {
+    let (tx, rx) = event_channel();
+    let mut sideways = OpenedEventSource {
+        id: dev.id.clone(),
+        name: dev.name.clone(),
+        path: dev.path.clone(),
+        caps: dev.caps,
+        // the middleman turns the stick into a d-pad and drops other axes
+        axes: vec![
+            (AbsoluteAxisType::ABS_HAT0X, AxisRange { min: -1, max: 1, fuzz: 0, flat: 0 }),
+            (AbsoluteAxisType::ABS_HAT0Y, AxisRange { min: -1, max: 1, fuzz: 0, flat: 0 }),
+        ],
+        rumble: dev.rumble.clone(),
+        motion: dev.motion.clone(),
+        devices: dev.devices.clone(),
+        chan: rx,
+        stop: StopFlag::default(),
+        threads: Vec::new(),
+    };
+
+    let stop = sideways.stop.clone();
+    sideways.threads.push(std::thread::spawn(move || {
+        let _ = joycon_ev_middleman(dev, tx, stop);
+    }));
+    sideways
+}
+
+pub fn combine_joycons(left: OpenedEventSource, right: OpenedEventSource) -> OpenedEventSource {
+    let (tx, rx) = event_channel();
+    let mut both = OpenedEventSource {
+        id: format!("{}+{}", left.id, right.id),
+        name: String::from("Nintendo Switch Both Joy-Cons"),
+        path: left.path.clone(),
+        caps: SourceCaps::FullX360,
+        axes: [left.axes.as_slice(), right.axes.as_slice()].concat(),
+        rumble: [left.rumble.as_slice(), right.rumble.as_slice()].concat(),
+        // aiming goes by the right hand, like on a Pro Controller
+        motion: [right.motion.as_slice(), left.motion.as_slice()].concat(),
+        devices: [left.devices.as_slice(), right.devices.as_slice()].concat(),
+        chan: rx,
+        stop: StopFlag::default(),
+        threads: Vec::new(),
+    };
+
+    for half in [left, right] {
+        let to_both = tx.clone();
+        let stop = both.stop.clone();
+        both.threads.push(std::thread::spawn(move || {
+            while let Some(ev) = half.recv_until(&stop) {
+                if to_both.send(ev).is_err() {
+                    return;
+                }
+            }
+        }));
+    }
+
+    both
+}
+
 fn actually_wait(dev: OpenedEventSource, out: mpsc::Sender<Option<OpenedEventSource>>) {
     let mut pressed_l = false;
     let mut pressed_r = false;
     loop {
-        let recv = dev.chan.recv();
+        let recv = dev.chan.recv_timeout(POLL_INTERVAL);
         if out.send(None).is_err() {
             return;
         }
 
-        if let Ok(ev) = recv {
-            match ev.kind() {
-                InputEventKind::Key(Key::BTN_TR) => pressed_r = if ev.value() == 1 { true } else { false },
-                InputEventKind::Key(Key::BTN_TL) => pressed_l = if ev.value() == 1 { true } else { false },
+        match recv {
+            Ok(ev) => match ev.kind() {
+                InputEventKind::Key(Key::BTN_TR) => pressed_r = ev.value() == 1,
+                InputEventKind::Key(Key::BTN_TL) => pressed_l = ev.value() == 1,
                 _ => (),
-            }
-        }
-
-        if recv.is_err() {
-            return;
+            },
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
         }
 
         if pressed_l && pressed_r {
-            out.send(Some(dev)).unwrap();
+            // the pairing might have been abandoned meanwhile, dropping releases the source
+            let _ = out.send(Some(dev));
             return;
         }
     }
 }
 
-struct TwoJoycons {
-    left: Option<OpenedEventSource>,
-    right: Option<OpenedEventSource>,
-}
-
-impl TwoJoycons {
-    fn have_both(&self) -> bool {
-        self.left.is_some() && self.right.is_some()
-    }
-    fn have_one(&self) -> bool {
-        self.left.is_some() || self.right.is_some()
-    }
-}
-
-fn joycon_ev_middleman(dev: OpenedEventSource, out: mpsc::Sender<InputEvent>) -> Result<()> {
+fn joycon_ev_middleman(dev: OpenedEventSource, out: EventSender, stop: StopFlag) -> Result<()> {
     let mut last_hatx = 0;
     let mut last_haty = 0;
     let is_right = dev.name.contains("Right");
-    loop {
-        for ev in dev.chan.recv() {
-            match ev.kind() {
-                InputEventKind::Key(key) => {
-                    if is_right {
-                        match key {
-                            Key::BTN_EAST => out.send(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, ev.value()))?,
-                            Key::BTN_WEST => out.send(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, ev.value()))?,
-                            Key::BTN_SOUTH => out.send(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, ev.value()))?,
-                            Key::BTN_NORTH => out.send(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, ev.value()))?,
-                            Key::BTN_TL2 => out.send(InputEvent::new(EventType::KEY, Key::BTN_TR.0, ev.value()))?,
-                            Key::BTN_TR => continue,
-                            Key::BTN_MODE => out.send(InputEvent::new(EventType::KEY, Key::BTN_SELECT.0, ev.value()))?,
-                            _ => out.send(ev)?,
+    while let Some(ev) = dev.recv_until(&stop) {
+        let send = |new: InputEvent| out.send(remapped(new, &ev));
+        match ev.kind() {
+            InputEventKind::Key(key) => {
+                if is_right {
+                    match key {
+                        Key::BTN_EAST => send(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, ev.value()))?,
+                        Key::BTN_WEST => send(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, ev.value()))?,
+                        Key::BTN_SOUTH => send(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, ev.value()))?,
+                        Key::BTN_NORTH => send(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, ev.value()))?,
+                        Key::BTN_TL2 => send(InputEvent::new(EventType::KEY, Key::BTN_TR.0, ev.value()))?,
+                        Key::BTN_TR => continue,
+                        Key::BTN_MODE => send(InputEvent::new(EventType::KEY, Key::BTN_SELECT.0, ev.value()))?,
+                        _ => send(ev)?,
+                    };
+                } else {
+                    match key {
+                        Key::BTN_TR => send(InputEvent::new(EventType::KEY, Key::BTN_TL.0, ev.value())),
+                        Key::BTN_TR2 => send(InputEvent::new(EventType::KEY, Key::BTN_TR.0, ev.value())),
+                        Key::BTN_TL => continue,
+                        _ => send(ev),
+                    }?;
+                }
+            },
+            InputEventKind::AbsAxis(abs) => {
+                match abs {
+                    AbsoluteAxisType::ABS_HAT0X => {
+                        match ev.value() {
+                            1 => send(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, 1))?,
+                            0 => {
+                                send(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, 0))?;
+                                send(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 0))?;
+                            },
+                            -1 => send(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 1))?,
+                            _ => unreachable!("Joycons can't make these events"),
                         };
-                    } else {
-                        match key {
-                            Key::BTN_TR => out.send(InputEvent::new(EventType::KEY, Key::BTN_TL.0, ev.value())),
-                            Key::BTN_TR2 => out.send(InputEvent::new(EventType::KEY, Key::BTN_TR.0, ev.value())),
-                            Key::BTN_TL => continue,
-                            _ => out.send(ev),
-                        }.unwrap();
-                    }
-                },
-                InputEventKind::AbsAxis(abs) => {
-                    match abs {
-                        AbsoluteAxisType::ABS_HAT0X => {
-                            match ev.value() {
-                                1 => out.send(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, 1))?,
-                                0 => {
-                                    out.send(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, 0))?;
-                                    out.send(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 0))?;
-                                },
-                                -1 => out.send(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 1))?,
-                                _ => unreachable!("Joycons can't make these events"),
-                            };
-                        },
-                        AbsoluteAxisType::ABS_HAT0Y => {
-                            match ev.value() {
-                                1 => out.send(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 1))?,
-                                0 => {
-                                    out.send(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 0))?;
-                                    out.send(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, 0))?;
-                                },
-                                -1 => out.send(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, 1))?,
-                                _ => unreachable!("Joycons can't make these events"),
-                            };
-                        },
-                        AbsoluteAxisType::ABS_Y | AbsoluteAxisType::ABS_RY
-                        | AbsoluteAxisType::ABS_X | AbsoluteAxisType::ABS_RX => {
-                            let code: u16;
-                            let mut mult: i32;
-                            let last: &mut i32;
-                            if abs == AbsoluteAxisType::ABS_X || abs == AbsoluteAxisType::ABS_Y {
-                                mult = 1;
-                            } else {
-                                mult = -1;
-                            }
-
-                            if abs == AbsoluteAxisType::ABS_RX || abs == AbsoluteAxisType::ABS_X {
-                                code = AbsoluteAxisType::ABS_HAT0X.0;
-                                last = &mut last_hatx;
-                                mult *= -1;
-                            } else {
-                                code = AbsoluteAxisType::ABS_HAT0Y.0;
-                                last = &mut last_haty;
-                            }
-
-                            let val = if ev.value() < -10000 {
-                                -1 * mult
-                            } else if ev.value() > 10000 {
-                                1 * mult
-                            } else {
-                                0
-                            };
-                            if *last != val {
-                                out.send(InputEvent::new(EventType::ABSOLUTE, code, val)).unwrap();
-                                *last = val;
-                            }
+                    },
+                    AbsoluteAxisType::ABS_HAT0Y => {
+                        match ev.value() {
+                            1 => send(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 1))?,
+                            0 => {
+                                send(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 0))?;
+                                send(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, 0))?;
+                            },
+                            -1 => send(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, 1))?,
+                            _ => unreachable!("Joycons can't make these events"),
+                        };
+                    },
+                    AbsoluteAxisType::ABS_Y | AbsoluteAxisType::ABS_RY
+                    | AbsoluteAxisType::ABS_X | AbsoluteAxisType::ABS_RX => {
+                        let code: u16;
+                        let mut mult: i32;
+                        let last: &mut i32;
+                        if abs == AbsoluteAxisType::ABS_X || abs == AbsoluteAxisType::ABS_Y {
+                            mult = 1;
+                        } else {
+                            mult = -1;
+                        }
+
+                        if abs == AbsoluteAxisType::ABS_RX || abs == AbsoluteAxisType::ABS_X {
+                            code = AbsoluteAxisType::ABS_HAT0X.0;
+                            last = &mut last_hatx;
+                            mult *= -1;
+                        } else {
+                            code = AbsoluteAxisType::ABS_HAT0Y.0;
+                            last = &mut last_haty;
+                        }
+
+                        let val = if ev.value() < -10000 {
+                            -mult
+                        } else if ev.value() > 10000 {
+                            mult
+                        } else {
+                            0
+                        };
+                        if *last != val {
+                            send(InputEvent::new(EventType::ABSOLUTE, code, val))?;
+                            *last = val;
                         }
-                        _ => continue,
                     }
-                },
-                _ => out.send(ev).unwrap(),
-            }
+                    _ => continue,
+                }
+            },
+            _ => send(ev)?,
         }
     }
+
+    Ok(())
 }
 
 // TL from left + TR from right = both
 // TR from left + TR2 from left = left
 // TL from right + TL2 from right = right
 
-fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Option<OpenedEventSource>, out: mpsc::Sender<Option<OpenedEventSource>>) {
+fn actually_wait_joycon(first: OpenedEventSource, partner: mpsc::Receiver<OpenedEventSource>, out: mpsc::Sender<Option<OpenedEventSource>>) {
+    let mut maybe_left = None;
+    let mut maybe_right = None;
+    let mut place = Some(first);
+
     let mut left_tl = false;
     let mut left_tr = false;
     let mut left_tr2 = false;
@@ -198,88 +499,56 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
     let mut right_tl2 = false;
 
     loop {
+        if let Some(dev) = place.take() {
+            if dev.name.contains("Left") {
+                maybe_left = Some(dev);
+            } else {
+                maybe_right = Some(dev);
+            }
+        }
+        // the other half may turn up in a later scan
+        if maybe_left.is_none() || maybe_right.is_none() {
+            place = partner.try_recv().ok();
+        }
+
+        // with both halves each one gets half of the wait
+        let wait = if maybe_left.is_some() && maybe_right.is_some() { POLL_INTERVAL / 2 } else { POLL_INTERVAL };
         if let Some(ref right) = maybe_right {
-            if let Ok(ev) = right.chan.try_recv() {
-                match ev.kind() {
-                    InputEventKind::Key(key) => {
-                        match key {
-                            Key::BTN_TR => right_tr = ev.value() != 0,
-                            Key::BTN_TL => right_tl = ev.value() != 0,
-                            Key::BTN_TL2 => right_tl2 = ev.value() != 0,
-                            _ => (),
-                        }
-                    },
+            match right.chan.recv_timeout(wait) {
+                Ok(ev) => match ev.kind() {
+                    InputEventKind::Key(Key::BTN_TR) => right_tr = ev.value() != 0,
+                    InputEventKind::Key(Key::BTN_TL) => right_tl = ev.value() != 0,
+                    InputEventKind::Key(Key::BTN_TL2) => right_tl2 = ev.value() != 0,
                     _ => (),
-                }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
             }
         }
         if let Some(ref left) = maybe_left {
-            if let Ok(ev) = left.chan.try_recv() {
-                match ev.kind() {
+            match left.chan.recv_timeout(wait) {
+                Ok(ev) => match ev.kind() {
                     InputEventKind::Key(Key::BTN_TL) => left_tl = ev.value() != 0,
                     InputEventKind::Key(Key::BTN_TR) => left_tr = ev.value() != 0,
                     InputEventKind::Key(Key::BTN_TR2) => left_tr2 = ev.value() != 0,
                     _ => (),
-                }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
             }
         }
 
         if left_tl && right_tr {
-            // combine both devices
-            let mut left = maybe_left.unwrap();
-            let right = maybe_right.unwrap();
-
-            left.caps = SourceCaps::FullX360;
-            left.name = String::from("Nintendo Switch Both Joy-Cons");
-
-            let to_left = left.chan_tx.clone();
-            std::thread::spawn(move || {
-                loop {
-                    for ev in right.chan.recv() {
-                        if to_left.send(ev).is_err() {
-                            return;
-                        }
-                    }
-                }
-            });
-            
-            out.send(Some(left)).unwrap();
+            let both = combine_joycons(maybe_left.unwrap(), maybe_right.unwrap());
+            let _ = out.send(Some(both));
             return;
         }
         if left_tr && left_tr2 {
-            let left = maybe_left.unwrap();
-            
-            let (tx, rx) = mpsc::channel();
-            let tx_2 = tx.clone();
-            let new_left = OpenedEventSource {
-                name: left.name.clone(),
-                path: left.path.clone(),
-                caps: left.caps,
-                chan: rx,
-                chan_tx: tx,
-            };
-
-            std::thread::spawn(move || joycon_ev_middleman(left, tx_2));
-
-            out.send(Some(new_left)).unwrap();
+            let _ = out.send(Some(sideways_joycon(maybe_left.unwrap())));
             return;
         }
         if right_tl && right_tl2 {
-            let right = maybe_right.unwrap();
-            
-            let (tx, rx) = mpsc::channel();
-            let tx_2 = tx.clone();
-            let new_right = OpenedEventSource {
-                name: right.name.clone(),
-                path: right.path.clone(),
-                caps: right.caps,
-                chan: rx,
-                chan_tx: tx,
-            };
-
-            std::thread::spawn(move || joycon_ev_middleman(right, tx_2));
-
-            out.send(Some(new_right)).unwrap();
+            let _ = out.send(Some(sideways_joycon(maybe_right.unwrap())));
             return;
         }
         if out.send(None).is_err() {
@@ -288,40 +557,72 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
     }
 }
 
-pub fn wait_for_lr(input: Vec<OpenedEventSource>) -> OpenedEventSource {
-    let (tx, rx) = mpsc::channel();
-    let mut joycons = TwoJoycons { left: None, right: None };
+// A Joy-Con waiter that has only one half so far, and where to hand it the
+// other one
+struct LoneJoycon {
+    left: bool,
+    partner: mpsc::Sender<OpenedEventSource>,
+}
 
-    for dev in input {
+fn spawn_waiters(input: Vec<OpenedEventSource>, tx: &mpsc::Sender<Option<OpenedEventSource>>, waiters: &mut Vec<JoinHandle<()>>,
+    lone: &mut Vec<LoneJoycon>)
+{
+    'next: for mut dev in input {
         let new_tx = tx.clone();
-        if dev.name.contains("Joy-Con") {
-            if dev.name.contains("Left") {
-                joycons.left = Some(dev);
-            } else {
-                joycons.right = Some(dev);
-            }
-        } else {
-            std::thread::spawn(|| actually_wait(dev, new_tx));
+        if !dev.name.contains("Joy-Con") {
+            waiters.push(std::thread::spawn(|| actually_wait(dev, new_tx)));
+            continue;
         }
 
-        if joycons.have_both() {
-            let new_tx = tx.clone();
-            std::thread::spawn(move || actually_wait_joycon(joycons.left.take(), joycons.right.take(), new_tx));
-            joycons = TwoJoycons { left: None, right: None }
+        // halves found in earlier scans get first pick
+        let left = dev.name.contains("Left");
+        while let Some(pos) = lone.iter().position(|half| half.left != left) {
+            match lone.remove(pos).partner.send(dev) {
+                Ok(()) => continue 'next,
+                // that waiter is done already
+                Err(SendError(back)) => dev = back,
+            }
         }
+        let (partner, more) = mpsc::channel();
+        lone.push(LoneJoycon { left, partner });
+        waiters.push(std::thread::spawn(move || actually_wait_joycon(dev, more, new_tx)));
     }
+}
 
-    if joycons.have_one() {
-        let new_tx = tx.clone();
-        std::thread::spawn(move || actually_wait_joycon(joycons.left.take(), joycons.right.take(), new_tx));
-    }
+// Returns the first source on which L and R are held together, or None once
+// `should_stop` says the caller is no longer interested. `find_new` is asked
+// for more sources every RESCAN_INTERVAL, so devices plugged in or released
+// by someone else meanwhile are picked up. Every other source is dropped and
+// thus released.
+pub fn wait_for_lr(mut find_new: impl FnMut() -> Vec<OpenedEventSource>, should_stop: impl Fn() -> bool) -> Option<OpenedEventSource> {
+    let (tx, rx) = mpsc::channel();
+    let mut waiters = Vec::new();
+    let mut lone = Vec::new();
+    let mut next_scan = Instant::now();
+
+    let mut found = None;
+    while !should_stop() {
+        if Instant::now() >= next_scan {
+            spawn_waiters(find_new(), &tx, &mut waiters, &mut lone);
+            next_scan = Instant::now() + RESCAN_INTERVAL;
+        }
 
-    let mut recv = None;
-    while recv.is_none() {
-        recv = rx.recv().unwrap();
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Some(dev)) => {
+                found = Some(dev);
+                break;
+            },
+            Ok(None) | Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => unreachable!("we hold a sender"),
+        }
     }
 
-    recv.unwrap()
+    // waiters notice the closed channel and drop their sources
+    drop(rx);
+    for waiter in waiters {
+        let _ = waiter.join();
+    }
+    found
 }
 
 pub fn enumerate() -> Vec<Box<dyn EventSource>> {
@@ -335,9 +636,34 @@ pub fn enumerate() -> Vec<Box<dyn EventSource>> {
 impl fmt::Debug for dyn EventSource {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("EventSource")
+            .field("id", &self.id())
             .field("name", &self.name())
             .field("path", &self.path())
             .field("capabilities", &self.get_capabilities())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joycon(id: &str, name: &str, button: Key) -> (EventSender, OpenedEventSource) {
+        let (tx, mut dev) = OpenedEventSource::fake();
+        dev.id = id.to_string();
+        dev.name = name.to_string();
+        tx.send(InputEvent::new(EventType::KEY, button.0, 1)).unwrap();
+        (tx, dev)
+    }
+
+    #[test]
+    fn joycon_halves_pair_across_rescans() {
+        let (_left_tx, left) = joycon("event9", "Nintendo Switch Left Joy-Con", Key::BTN_TL);
+        let (_right_tx, right) = joycon("event10", "Nintendo Switch Right Joy-Con", Key::BTN_TR);
+        let mut scans = vec![vec![left], vec![right]].into_iter();
+        let deadline = Instant::now() + RESCAN_INTERVAL * 4;
+
+        let both = wait_for_lr(|| scans.next().unwrap_or_default(), || Instant::now() >= deadline).unwrap();
+        assert_eq!(both.id, "event9+event10");
+    }
+}
@@ -6,6 +6,8 @@ use evdev::{
     InputEventKind,
 };
 use std::path::Path;
+use crate::source::AxisRange;
+use log::debug;
 
 #[derive(Clone, Debug)]
 pub struct DmiQuirk {
@@ -19,6 +21,7 @@ pub struct DmiQuirk {
     pub remap_codes: Vec<InputRemap>, 
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Copy, Clone, Debug)]
 pub enum InputRemap {
     KeyToKey(Key, Key),
@@ -27,6 +30,20 @@ pub enum InputRemap {
 }
 
 impl InputRemap {
+    // The axis a remap produces and the range of the values it sends
+    pub fn output_axis(self) -> Option<(AbsoluteAxisType, AxisRange)> {
+        match self {
+            InputRemap::KeyToAbs(_, abs) => match abs {
+                AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ =>
+                    Some((abs, AxisRange { min: 0, max: 255, fuzz: 0, flat: 0 })),
+                AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y =>
+                    Some((abs, AxisRange { min: -1, max: 1, fuzz: 0, flat: 0 })),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     pub fn apply_quirk(self, input: InputEvent) -> Option<InputEvent> {
         if let InputEventKind::Key(input_key) = input.kind() {
             match self {
@@ -63,7 +80,7 @@ impl InputRemap {
                     if my_key != input_key {
                         return None;
                     }
-                    println!("Steam quick access menu launch goes here");
+                    debug!("Steam quick access menu launch goes here");
                 },
             }
         };
@@ -127,13 +144,13 @@ pub fn get_dmi_quirk(phys_path: &Path) -> Option<DmiQuirk> {
     let board_vendor = get_dmi("board_vendor");
 
     for quirk in quirks_vec.into_iter() {
-        let pn_match = match_str(&quirk.product_name, &product_name, quirk.relaxed_name);
-        let pv_match = match_str(&quirk.product_vendor, &product_vendor, quirk.relaxed_vendor);
-        let bn_match = match_str(&quirk.board_name, &board_name, quirk.relaxed_name);
-        let bv_match = match_str(&quirk.board_vendor, &board_vendor, quirk.relaxed_vendor);
+        let pn_match = match_str(quirk.product_name, &product_name, quirk.relaxed_name);
+        let pv_match = match_str(quirk.product_vendor, &product_vendor, quirk.relaxed_vendor);
+        let bn_match = match_str(quirk.board_name, &board_name, quirk.relaxed_name);
+        let bv_match = match_str(quirk.board_vendor, &board_vendor, quirk.relaxed_vendor);
         if pn_match && pv_match && bn_match && bv_match {
             if quirk.phys_path.is_empty() {
-                eprintln!("Note: Matched {} against empty path", phys_path.display());
+                debug!("Matched {} against empty path", phys_path.display());
             }
             return Some(quirk);
         }
@@ -0,0 +1,199 @@
+use std::{
+    env,
+    io,
+    net::TcpListener,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            io::{FromRawFd, RawFd},
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+    },
+    sync::Arc,
+    time::Duration,
+};
+use nix::sys::socket::{getsockname, SockAddr};
+use log::{error, warn};
+
+use crate::daemon::Daemon;
+
+// First fd passed by the service manager, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+fn pid_matches(pid: Option<&str>, my_pid: u32) -> bool {
+    pid.and_then(|v| v.parse::<u32>().ok()) == Some(my_pid)
+}
+
+// Fds handed over through LISTEN_PID/LISTEN_FDS, empty if they are not meant
+// for this process
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, my_pid: u32) -> Vec<RawFd> {
+    if !pid_matches(listen_pid, my_pid) {
+        return Vec::new();
+    }
+    let count = listen_fds.and_then(|v| v.parse::<RawFd>().ok()).unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + count.max(0)).collect()
+}
+
+// Half the interval systemd expects pings in, None if there is no watchdog
+fn parse_watchdog(watchdog_usec: Option<&str>, watchdog_pid: Option<&str>, my_pid: u32) -> Option<Duration> {
+    if watchdog_pid.is_some() && !pid_matches(watchdog_pid, my_pid) {
+        return None;
+    }
+    let usec = watchdog_usec?.parse::<u64>().ok().filter(|v| *v > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn listener_from_fd(fd: RawFd) -> io::Result<Listener> {
+    // the fds are ours once LISTEN_PID matched, nobody else closes them
+    match getsockname(fd)? {
+        SockAddr::Unix(_) => Ok(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) })),
+        SockAddr::Inet(_) => Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} is not a unix or TCP socket", fd))),
+    }
+}
+
+fn env_str(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+// Takes the fds systemd passed for socket activation. The variables are
+// removed so children don't think the fds are meant for them, which is only
+// sound before any other thread exists.
+pub fn take_listen_fds() -> Vec<RawFd> {
+    let fds = parse_listen_fds(env_str("LISTEN_PID").as_deref(), env_str("LISTEN_FDS").as_deref(),
+        std::process::id());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    fds
+}
+
+// Turns the fds from take_listen_fds into listeners
+pub fn listeners(fds: Vec<RawFd>) -> Vec<Listener> {
+    fds.into_iter()
+        .filter_map(|fd| match listener_from_fd(fd) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                warn!("Ignoring socket activation fd {}: {}", fd, e);
+                None
+            },
+        })
+        .collect()
+}
+
+fn notify_to(socket: &str, state: &str) -> io::Result<()> {
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    let sock = UnixDatagram::unbound()?;
+    sock.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+// Sends a state like READY=1 to the service manager, a no-op outside systemd
+pub fn notify(state: &str) {
+    if let Some(socket) = env_str("NOTIFY_SOCKET") {
+        if let Err(e) = notify_to(&socket, state) {
+            warn!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+// Pings the watchdog for as long as the daemon looks healthy. Missing pings
+// make systemd restart the service.
+pub fn watchdog(daemon: Arc<Daemon>) {
+    let interval = match parse_watchdog(env_str("WATCHDOG_USEC").as_deref(),
+        env_str("WATCHDOG_PID").as_deref(), std::process::id())
+    {
+        Some(i) => i,
+        None => return,
+    };
+
+    while !daemon.stopping.is_stopped() {
+        if daemon.is_healthy() {
+            notify("WATCHDOG=1");
+        } else {
+            error!("Health check failed, not pinging the watchdog");
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+    #[test]
+    fn listen_fds_need_our_pid() {
+        assert_eq!(parse_listen_fds(Some("42"), Some("2"), 42), vec![3, 4]);
+        assert!(parse_listen_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(parse_listen_fds(None, Some("2"), 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), None, 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("-1"), 42).is_empty());
+    }
+
+    #[test]
+    fn watchdog_interval_is_half_the_timeout() {
+        assert_eq!(parse_watchdog(Some("10000000"), None, 42), Some(Duration::from_secs(5)));
+        assert_eq!(parse_watchdog(Some("10000000"), Some("42"), 42), Some(Duration::from_secs(5)));
+        assert_eq!(parse_watchdog(Some("10000000"), Some("41"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+
+    #[test]
+    fn recognizes_activated_sockets() {
+        let dir = env::temp_dir().join(format!("rinputer4-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("activated.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let unix = UnixListener::bind(&path).unwrap().into_raw_fd();
+        assert!(matches!(listener_from_fd(unix).unwrap(), Listener::Unix(_)));
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        assert!(matches!(listener_from_fd(tcp).unwrap(), Listener::Tcp(_)));
+
+        // a plain file is not a socket at all
+        let file = std::fs::File::open("/proc/self/stat").unwrap();
+        assert!(listener_from_fd(file.as_raw_fd()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn notifies_a_stand_in_manager() {
+        let dir = env::temp_dir().join(format!("rinputer4-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let manager = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        notify_to(path.to_str().unwrap(), "WATCHDOG=1").unwrap();
+
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn notifies_an_abstract_socket() {
+        let name = format!("rinputer4-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let manager = UnixDatagram::bind_addr(&addr).unwrap();
+
+        notify_to(&format!("@{}", name), "STOPPING=1").unwrap();
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STOPPING=1");
+    }
+}
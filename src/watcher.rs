@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use evdev::Device;
+
+use crate::{
+    daemon::Daemon,
+    events::{self, Event},
+    sink::{Sink, SinkId},
+    source::event,
+};
+
+static SCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+fn list_event_nodes() -> Vec<PathBuf> {
+    let dir = match fs::read_dir("/dev/input") {
+        Ok(d) => d,
+        Err(_) => return Vec::new(),
+    };
+
+    dir.filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+// Tracks which event nodes exist and which of them are gamepads.
+// Non-gamepads are kept as None so they are only opened once.
+struct Hotplug {
+    known: HashMap<PathBuf, Option<String>>,
+}
+
+impl Hotplug {
+    fn new() -> Self {
+        let mut ret = Self { known: HashMap::new() };
+        ret.scan();
+        ret
+    }
+
+    fn scan(&mut self) -> Vec<Event> {
+        let mut ret = Vec::new();
+        let nodes = list_event_nodes();
+
+        self.known.retain(|path, name| {
+            if nodes.contains(path) {
+                return true;
+            }
+            if name.is_some() {
+                ret.push(Event::SourceRemoved { path: path.display().to_string() });
+            }
+            false
+        });
+
+        for path in nodes {
+            if self.known.contains_key(&path) {
+                continue;
+            }
+            // udev might not have fixed up permissions yet, retry on next scan
+            let dev = match Device::open(&path) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            if event::is_gamepad(&path, &dev) {
+                let name = dev.name().unwrap_or("Linux event device").to_string();
+                ret.push(Event::SourceAdded { path: path.display().to_string(), name: name.clone() });
+                self.known.insert(path, Some(name));
+            } else {
+                self.known.insert(path, None);
+            }
+        }
+
+        ret
+    }
+}
+
+pub fn watch(daemon: Arc<Daemon>) {
+    let mut hotplug = Hotplug::new();
+    while !daemon.stopping.is_stopped() {
+        daemon.watcher_alive();
+        std::thread::sleep(SCAN_INTERVAL);
+
+        let found = hotplug.scan();
+        let added = found.iter().any(|ev| matches!(ev, Event::SourceAdded { .. }));
+        for ev in found {
+            events::publish(ev);
+        }
+        if added {
+            daemon.state.try_restore(&mut daemon.sinks.lock().unwrap());
+        }
+
+        // sinks that lost their source wait for it like restored ones, so
+        // replugging a pad brings its sink back
+        let lost: Vec<(SinkId, Box<dyn Sink>)> = {
+            let mut sinks = daemon.sinks.lock().unwrap();
+            let ids: Vec<SinkId> = sinks.iter()
+                .filter(|(_, sink)| sink.take_source_lost())
+                .map(|(id, _)| id)
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| sinks.remove(id).map(|s| (id, s)))
+                .inspect(|(_, s)| daemon.state.requeue(s.as_ref()))
+                .collect()
+        };
+        for (sink, victim) in lost {
+            events::publish(Event::SinkSourceLost { sink, source: victim.source_name() });
+            drop(victim);
+            events::publish(Event::SinkDeleted { sink });
+        }
+    }
+}